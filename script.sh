@@ -1,7 +1,6 @@
-channel() {
+render() {
     cargo run --release -- --disc 3.0 -i 400000000 -w 1920 -h 1080 --div 4 $@
 }
 
-channel -s 2500 blue.png
-channel -s 1000 green.png
-channel -s 500 red.png
+# Red, green and blue channels in a single sampling pass
+render --channel 500 --channel 1000 --channel 2500 nebula.png
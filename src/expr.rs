@@ -0,0 +1,653 @@
+//! Custom iteration formulas, parsed from expressions like
+//! `z^2 + c + 0.3*conj(z)`.
+//!
+//! Expressions are over the complex variables `z` and `c`, the constants
+//! `i`, `pi` and `e`, the operators `+ - * / ^` and the functions listed in
+//! [`Func`]. They're compiled into a program for a small stack machine, with
+//! constant subexpressions folded and integer powers multiplied out.
+use crate::formula::{Complex, Formula};
+use crate::Opt;
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// Deepest stack a program may use
+const MAX_STACK: usize = 16;
+
+/// Largest integer power expanded into multiplications
+const MAX_INT_POWER: f32 = 64.;
+
+fn mul((a, b): Complex, (c, d): Complex) -> Complex {
+    (a * c - b * d, a * d + b * c)
+}
+
+fn div((a, b): Complex, (c, d): Complex) -> Complex {
+    let n = c * c + d * d;
+    ((a * c + b * d) / n, (b * c - a * d) / n)
+}
+
+fn exp((a, b): Complex) -> Complex {
+    let r = a.exp();
+    (r * b.cos(), r * b.sin())
+}
+
+fn log((a, b): Complex) -> Complex {
+    ((a * a + b * b).sqrt().ln(), b.atan2(a))
+}
+
+/// Raise z to an integer power by repeated squaring
+fn powi(z: Complex, n: i32) -> Complex {
+    let (mut result, mut base, mut k) = ((1., 0.), z, n.unsigned_abs());
+    while k > 0 {
+        if k & 1 == 1 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        k >>= 1;
+    }
+
+    if n < 0 {
+        div((1., 0.), result)
+    } else {
+        result
+    }
+}
+
+/// Raise z to a complex power, on the principal branch
+fn pow(z: Complex, w: Complex) -> Complex {
+    if z == (0., 0.) {
+        return (0., 0.);
+    }
+    exp(mul(w, log(z)))
+}
+
+/// Functions of a single complex argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Func {
+    Neg,
+    Conj,
+    /// Modulus
+    Abs,
+    Re,
+    Im,
+    Arg,
+    Sqrt,
+    Exp,
+    Log,
+    Sin,
+    Cos,
+    Tan,
+    Sinh,
+    Cosh,
+    Tanh,
+}
+
+impl Func {
+    fn by_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "conj" => Self::Conj,
+            "abs" => Self::Abs,
+            "re" => Self::Re,
+            "im" => Self::Im,
+            "arg" => Self::Arg,
+            "sqrt" => Self::Sqrt,
+            "exp" => Self::Exp,
+            "log" | "ln" => Self::Log,
+            "sin" => Self::Sin,
+            "cos" => Self::Cos,
+            "tan" => Self::Tan,
+            "sinh" => Self::Sinh,
+            "cosh" => Self::Cosh,
+            "tanh" => Self::Tanh,
+            _ => return None,
+        })
+    }
+
+    fn apply(self, z: Complex) -> Complex {
+        let (a, b) = z;
+        match self {
+            Self::Neg => (-a, -b),
+            Self::Conj => (a, -b),
+            Self::Abs => ((a * a + b * b).sqrt(), 0.),
+            Self::Re => (a, 0.),
+            Self::Im => (b, 0.),
+            Self::Arg => (b.atan2(a), 0.),
+            Self::Sqrt => {
+                let r = (a * a + b * b).sqrt().sqrt();
+                let theta = b.atan2(a) / 2.;
+                (r * theta.cos(), r * theta.sin())
+            }
+            Self::Exp => exp(z),
+            Self::Log => log(z),
+            Self::Sin => (a.sin() * b.cosh(), a.cos() * b.sinh()),
+            Self::Cos => (a.cos() * b.cosh(), -a.sin() * b.sinh()),
+            Self::Tan => div(Self::Sin.apply(z), Self::Cos.apply(z)),
+            Self::Sinh => (a.sinh() * b.cos(), a.cosh() * b.sin()),
+            Self::Cosh => (a.cosh() * b.cos(), a.sinh() * b.sin()),
+            Self::Tanh => div(Self::Sinh.apply(z), Self::Cosh.apply(z)),
+        }
+    }
+
+    /// Whether f(conj(z)) = conj(f(z)), so that the function preserves the
+    /// symmetry of orbits about the real axis
+    fn symmetric(self) -> bool {
+        !matches!(self, Self::Im | Self::Arg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+impl BinOp {
+    fn apply(self, (a, b): Complex, (c, d): Complex) -> Complex {
+        match self {
+            Self::Add => (a + c, b + d),
+            Self::Sub => (a - c, b - d),
+            Self::Mul => mul((a, b), (c, d)),
+            Self::Div => div((a, b), (c, d)),
+            Self::Pow => pow((a, b), (c, d)),
+        }
+    }
+}
+
+/// Parsed expression tree
+#[derive(Debug, Clone)]
+enum Node {
+    Const(Complex),
+    Z,
+    C,
+    Call(Func, Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    /// Evaluate constant subexpressions
+    fn fold(self) -> Node {
+        match self {
+            Node::Call(f, arg) => match arg.fold() {
+                Node::Const(v) => Node::Const(f.apply(v)),
+                arg => Node::Call(f, Box::new(arg)),
+            },
+            Node::Binary(op, l, r) => match (l.fold(), r.fold()) {
+                (Node::Const(a), Node::Const(b)) => Node::Const(op.apply(a, b)),
+                (l, r) => Node::Binary(op, Box::new(l), Box::new(r)),
+            },
+            node => node,
+        }
+    }
+
+    /// Append instructions leaving the value of this node on the stack,
+    /// returning the stack depth needed
+    fn emit(&self, ops: &mut Vec<Op>) -> usize {
+        match self {
+            Node::Const(_) | Node::Z | Node::C => {
+                ops.push(Op::Push(self.operand().unwrap()));
+                1
+            }
+            Node::Call(f, arg) => {
+                let depth = arg.emit(ops);
+                ops.push(Op::Call(*f));
+                depth
+            }
+            Node::Binary(BinOp::Pow, base, exponent) => match **exponent {
+                Node::Const((n, 0.)) if n.fract() == 0. && n.abs() <= MAX_INT_POWER => {
+                    let depth = base.emit(ops);
+                    ops.push(match n {
+                        2. => Op::Square,
+                        n => Op::PowInt(n as i32),
+                    });
+                    depth
+                }
+                _ => Self::emit_binary(BinOp::Pow, base, exponent, ops),
+            },
+            Node::Binary(op, l, r) => Self::emit_binary(*op, l, r, ops),
+        }
+    }
+
+    fn emit_binary(op: BinOp, l: &Node, r: &Node, ops: &mut Vec<Op>) -> usize {
+        let left = l.emit(ops);
+
+        // Leaves are read directly rather than pushed and popped
+        if let Some(operand) = r.operand() {
+            ops.push(Op::BinaryWith(op, operand));
+            return left;
+        }
+
+        let right = r.emit(ops);
+        ops.push(Op::Binary(op));
+        left.max(right + 1)
+    }
+
+    fn operand(&self) -> Option<Operand> {
+        match *self {
+            Node::Const(v) => Some(Operand::Const(v)),
+            Node::Z => Some(Operand::Z),
+            Node::C => Some(Operand::C),
+            _ => None,
+        }
+    }
+}
+
+/// Value read by an instruction
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Const(Complex),
+    Z,
+    C,
+}
+
+impl Operand {
+    fn get(self, z: Complex, c: Complex) -> Complex {
+        match self {
+            Self::Const(v) => v,
+            Self::Z => z,
+            Self::C => c,
+        }
+    }
+}
+
+/// Stack machine instruction
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Push(Operand),
+    Call(Func),
+    /// Combine the top two values
+    Binary(BinOp),
+    /// Combine the top value with an operand
+    BinaryWith(BinOp, Operand),
+    Square,
+    PowInt(i32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f32),
+    Ident(String),
+    Sym(char),
+    End,
+}
+
+/// Split an expression into tokens, each with its column
+fn tokenize(s: &str) -> Result<Vec<(Token, usize)>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        let start = i;
+
+        if ch.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let token = if ch.is_ascii_digit() || ch == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+
+            // Exponent, as in 1e-3
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+
+            let text: String = chars[start..i].iter().collect();
+            match text.parse() {
+                Ok(v) => Token::Num(v),
+                Err(_) => bail!("Invalid number {:?} at column {}", text, start + 1),
+            }
+        } else if ch.is_alphabetic() || ch == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            Token::Ident(chars[start..i].iter().collect())
+        } else if "+-*/^(),=".contains(ch) {
+            i += 1;
+            Token::Sym(ch)
+        } else {
+            bail!("Unexpected character {:?} at column {}", ch, start + 1);
+        };
+
+        tokens.push((token, start + 1));
+    }
+
+    tokens.push((Token::End, chars.len() + 1));
+    Ok(tokens)
+}
+
+/// Recursive descent parser, from lowest to highest precedence
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn column(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, sym: char) -> bool {
+        if *self.peek() == Token::Sym(sym) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, sym: char) -> Result<()> {
+        if !self.eat(sym) {
+            bail!("Expected {:?} at column {}", sym, self.column());
+        }
+        Ok(())
+    }
+
+    /// Sums and differences
+    fn expr(&mut self) -> Result<Node> {
+        let mut node = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                BinOp::Add
+            } else if self.eat('-') {
+                BinOp::Sub
+            } else {
+                return Ok(node);
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.term()?));
+        }
+    }
+
+    /// Products and quotients
+    fn term(&mut self) -> Result<Node> {
+        let mut node = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                BinOp::Mul
+            } else if self.eat('/') {
+                BinOp::Div
+            } else {
+                return Ok(node);
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    /// Negation, binding looser than powers so that -z^2 is -(z^2)
+    fn unary(&mut self) -> Result<Node> {
+        if self.eat('-') {
+            Ok(Node::Call(Func::Neg, Box::new(self.unary()?)))
+        } else if self.eat('+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    /// Right associative powers
+    fn power(&mut self) -> Result<Node> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            let exponent = self.unary()?;
+            Ok(Node::Binary(BinOp::Pow, Box::new(base), Box::new(exponent)))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        let column = self.column();
+        match self.next() {
+            Token::Num(v) => Ok(Node::Const((v, 0.))),
+            Token::Sym('(') => {
+                let node = self.expr()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Token::Ident(name) if *self.peek() == Token::Sym('(') => {
+                self.pos += 1;
+                let node = if name == "pow" {
+                    let base = self.expr()?;
+                    self.expect(',')?;
+                    let exponent = self.expr()?;
+                    Node::Binary(BinOp::Pow, Box::new(base), Box::new(exponent))
+                } else {
+                    let Some(f) = Func::by_name(&name) else {
+                        bail!("Unknown function {:?} at column {}", name, column);
+                    };
+                    Node::Call(f, Box::new(self.expr()?))
+                };
+                self.expect(')')?;
+                Ok(node)
+            }
+            Token::Ident(name) => Ok(match name.as_str() {
+                "z" => Node::Z,
+                "c" => Node::C,
+                "i" => Node::Const((0., 1.)),
+                "pi" => Node::Const((std::f32::consts::PI, 0.)),
+                "e" => Node::Const((std::f32::consts::E, 0.)),
+                _ => bail!("Unknown variable {:?} at column {}", name, column),
+            }),
+            Token::End => bail!("Unexpected end of formula"),
+            Token::Sym(ch) => bail!("Unexpected {:?} at column {}", ch, column),
+        }
+    }
+}
+
+/// A compiled formula for the next point of an orbit
+#[derive(Debug, Clone)]
+pub struct Expr {
+    /// Source text, as given
+    source: String,
+    ops: Vec<Op>,
+}
+
+impl FromStr for Expr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+
+        // Allow writing the formula as an assignment to z
+        if parser.tokens.len() > 2 && parser.tokens[1].0 == Token::Sym('=') {
+            if parser.tokens[0].0 != Token::Ident("z".into()) {
+                bail!("Formulas can only assign to z");
+            }
+            parser.pos = 2;
+        }
+
+        let node = parser.expr()?;
+        if *parser.peek() != Token::End {
+            bail!("Unexpected input at column {}", parser.column());
+        }
+
+        let mut ops = Vec::new();
+        if node.fold().emit(&mut ops) > MAX_STACK {
+            bail!("Formula is nested too deeply");
+        }
+
+        Ok(Self {
+            source: s.trim().to_string(),
+            ops,
+        })
+    }
+}
+
+impl Expr {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the orbit of conj(c) is the mirror image of the orbit of c,
+    /// which holds if every constant is real and every function symmetric
+    pub fn symmetric(&self) -> bool {
+        self.ops.iter().all(|op| match op {
+            Op::Push(Operand::Const((_, b))) => *b == 0.,
+            Op::BinaryWith(_, Operand::Const((_, b))) => *b == 0.,
+            Op::Call(f) => f.symmetric(),
+            _ => true,
+        })
+    }
+
+    fn eval(&self, z: Complex, c: Complex) -> Complex {
+        // The top of the stack is kept in `acc`, and only values below it in
+        // the array, so simple formulas never touch memory
+        let mut stack = [(0., 0.); MAX_STACK];
+        let mut top = 0;
+        let mut acc = (0., 0.);
+
+        for op in &self.ops {
+            match *op {
+                Op::Push(operand) => {
+                    stack[top] = acc;
+                    top += 1;
+                    acc = operand.get(z, c);
+                }
+                Op::Call(f) => acc = f.apply(acc),
+                Op::Binary(op) => {
+                    top -= 1;
+                    acc = op.apply(stack[top], acc);
+                }
+                Op::BinaryWith(op, operand) => acc = op.apply(acc, operand.get(z, c)),
+                Op::Square => acc = mul(acc, acc),
+                Op::PowInt(n) => acc = powi(acc, n),
+            }
+        }
+
+        acc
+    }
+}
+
+/// A custom formula given by an expression
+pub struct Custom {
+    expr: Expr,
+    escape: f32,
+}
+
+impl Custom {
+    pub fn new(args: &Opt, expr: &Expr) -> Self {
+        Self {
+            expr: expr.clone(),
+            escape: args.disc * args.disc,
+        }
+    }
+}
+
+impl Formula for Custom {
+    fn step(&self, z: Complex, c: Complex) -> Complex {
+        self.expr.eval(z, c)
+    }
+
+    /// Orbits escaping the cutoff disc are assumed to diverge. Orbits which
+    /// overflow to infinity or NaN count as escaped too
+    fn escaped(&self, (a, b): Complex) -> bool {
+        let r = a * a + b * b;
+        r.is_nan() || r > self.escape
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Expr {
+        s.parse().unwrap()
+    }
+
+    fn error(s: &str) -> String {
+        s.parse::<Expr>().unwrap_err().to_string()
+    }
+
+    fn assert_close((a, b): Complex, (c, d): Complex) {
+        assert!(
+            (a - c).abs() < 1e-5 && (b - d).abs() < 1e-5,
+            "{:?} != {:?}",
+            (a, b),
+            (c, d)
+        );
+    }
+
+    #[test]
+    fn negation_binds_looser_than_powers() {
+        // -(z^2) rather than (-z)^2
+        assert_close(parse("-z^2").eval((1., 1.), (0., 0.)), (0., -2.));
+    }
+
+    #[test]
+    fn powers_are_right_associative() {
+        // z^(3^2) = z^9 rather than (z^3)^2 = z^6
+        assert_close(parse("z^3^2").eval((2., 0.), (0., 0.)), (512., 0.));
+    }
+
+    #[test]
+    fn negative_integer_powers_are_folded() {
+        let expr = parse("z^-2");
+        assert!(matches!(
+            expr.ops.as_slice(),
+            [Op::Push(Operand::Z), Op::PowInt(-2)]
+        ));
+        assert_close(expr.eval((2., 0.), (0., 0.)), (0.25, 0.));
+    }
+
+    #[test]
+    fn assignment_to_z() {
+        let expr = parse("z = z^2 + c");
+        assert_close(expr.eval((1., 2.), (0.5, -1.)), (-2.5, 3.));
+        assert_eq!(error("c = z"), "Formulas can only assign to z");
+    }
+
+    #[test]
+    fn errors_report_columns() {
+        assert_eq!(error("z ^ (2"), "Expected ')' at column 7");
+        assert_eq!(error("z^2 + q"), "Unknown variable \"q\" at column 7");
+        assert_eq!(error("foo(z)"), "Unknown function \"foo\" at column 1");
+        assert_eq!(error("z $ c"), "Unexpected character '$' at column 3");
+        assert_eq!(error("3z"), "Unexpected input at column 2");
+        assert_eq!(error("z^2 +"), "Unexpected end of formula");
+    }
+
+    #[test]
+    fn symmetry() {
+        assert!(parse("z^2 + c + 0.3*conj(z)").symmetric());
+        assert!(parse("abs(z) + c").symmetric());
+        assert!(!parse("z^2 + i*c").symmetric());
+        assert!(!parse("z^2 + c + im(z)").symmetric());
+        assert!(!parse("z^2 + c + arg(z)").symmetric());
+    }
+
+    #[test]
+    fn eval_matches_hand_computation() {
+        // z^2 = -3 + 4i, conj(z) = 1 - 2i
+        let expr = parse("z^2 + c + 0.3*conj(z)");
+        assert_close(expr.eval((1., 2.), (0.5, -1.)), (-2.2, 2.4));
+    }
+}
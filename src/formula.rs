@@ -0,0 +1,366 @@
+//! Iterated functions whose orbits are traced.
+//!
+//! Points are complex numbers held as `(re, im)` pairs. Every built-in
+//! formula raises a variant of z to `--power` before adding c, so they
+//! share the Mandelbrot set's escape radius.
+use crate::Opt;
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+pub type Complex = (f32, f32);
+
+/// A fractal iterating z -> f(z, c)
+pub trait Formula: Send + 'static {
+    /// Starting point of the orbit of c
+    fn init(&self, _c: Complex) -> Complex {
+        (0., 0.)
+    }
+
+    /// Next point of the orbit
+    fn step(&self, z: Complex, c: Complex) -> Complex;
+
+    /// Whether the orbit is known to escape once it reaches z
+    fn escaped(&self, z: Complex) -> bool;
+
+    /// Whether c is known to be bounded without iterating it
+    fn in_interior(&self, _c: Complex) -> bool {
+        false
+    }
+}
+
+/// Iterate the orbit of c, until it escapes
+pub fn iterate<F: Formula>(formula: &F, c: Complex) -> impl Iterator<Item = Complex> + '_ {
+    let mut z = formula.init(c);
+    std::iter::from_fn(move || {
+        z = formula.step(z, c);
+        (!formula.escaped(z)).then_some(z)
+    })
+}
+
+/// Built-in formulas, selectable by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormulaKind {
+    /// z^d + c
+    Mandelbrot,
+    /// (|re z| + i|im z|)^d + c
+    BurningShip,
+    /// conj(z)^d + c, also known as the Mandelbar
+    Tricorn,
+    /// |re z^d| + i im z^d + c
+    Celtic,
+    /// (|re z| - i im z)^d + c
+    Perpendicular,
+}
+
+impl FromStr for FormulaKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "mandelbrot" | "multibrot" => Self::Mandelbrot,
+            "burning-ship" => Self::BurningShip,
+            "tricorn" | "mandelbar" => Self::Tricorn,
+            "celtic" => Self::Celtic,
+            "perpendicular" => Self::Perpendicular,
+            _ => bail!(
+                "Unknown formula {:?}, expected mandelbrot, burning-ship, tricorn, celtic or perpendicular",
+                s
+            ),
+        })
+    }
+}
+
+impl FormulaKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Mandelbrot => "mandelbrot",
+            Self::BurningShip => "burning-ship",
+            Self::Tricorn => "tricorn",
+            Self::Celtic => "celtic",
+            Self::Perpendicular => "perpendicular",
+        }
+    }
+
+    /// Whether the orbit of conj(c) is the mirror image of the orbit of c
+    pub fn symmetric(self) -> bool {
+        self != Self::BurningShip
+    }
+}
+
+/// Run `$body` with `$f` bound to the formula chosen in `$args`, so that code
+/// generic over [`Formula`] is monomorphized for each of them
+macro_rules! with_formula {
+    ($args:expr, $f:ident => $body:expr) => {{
+        let args: &$crate::Opt = $args;
+        with_formula!(@base args, formula => match args.julia() {
+            Some(c) => {
+                let $f = $crate::formula::Julia::new(formula, c);
+                $body
+            }
+            None => {
+                let $f = formula;
+                $body
+            }
+        })
+    }};
+    (@base $args:ident, $f:ident => $body:expr) => {{
+        use $crate::formula::*;
+        if let Some(expr) = &$args.expr {
+            let $f = $crate::expr::Custom::new($args, expr);
+            $body
+        } else {
+            match $args.formula {
+                FormulaKind::Mandelbrot => {
+                    let $f = Mandelbrot::new($args);
+                    $body
+                }
+                FormulaKind::BurningShip => {
+                    let $f = BurningShip::new($args);
+                    $body
+                }
+                FormulaKind::Tricorn => {
+                    let $f = Tricorn::new($args);
+                    $body
+                }
+                FormulaKind::Celtic => {
+                    let $f = Celtic::new($args);
+                    $body
+                }
+                FormulaKind::Perpendicular => {
+                    let $f = Perpendicular::new($args);
+                    $body
+                }
+            }
+        }
+    }};
+}
+pub(crate) use with_formula;
+
+/// Exponent of z in z^d + c, with fast paths for common integer powers
+#[derive(Debug, Clone, Copy)]
+pub enum Power {
+    Square,
+    Cube,
+    Fourth,
+    Int(u32),
+    Real(f32),
+}
+
+impl Power {
+    pub fn new(d: f32) -> Self {
+        match d {
+            2. => Self::Square,
+            3. => Self::Cube,
+            4. => Self::Fourth,
+            d if d.fract() == 0. && d > 0. => Self::Int(d as u32),
+            d => Self::Real(d),
+        }
+    }
+
+    /// Raise a + bi to this power
+    fn apply(self, (a, b): Complex) -> Complex {
+        match self {
+            Self::Square => (a * a - b * b, 2. * a * b),
+            Self::Cube => {
+                let (a2, b2) = (a * a, b * b);
+                (a * (a2 - 3. * b2), b * (3. * a2 - b2))
+            }
+            Self::Fourth => {
+                let (a2, b2) = (a * a - b * b, 2. * a * b);
+                (a2 * a2 - b2 * b2, 2. * a2 * b2)
+            }
+            Self::Int(d) => {
+                let (mut ra, mut rb) = (a, b);
+                for _ in 1..d {
+                    (ra, rb) = (ra * a - rb * b, ra * b + rb * a);
+                }
+                (ra, rb)
+            }
+            Self::Real(d) => {
+                // Polar form, using the principal branch of the argument
+                let r = (a * a + b * b).powf(d / 2.);
+                let theta = b.atan2(a) * d;
+                (r * theta.cos(), r * theta.sin())
+            }
+        }
+    }
+}
+
+/// Squared radius beyond which orbits of z^d + c escape
+fn escape_radius(args: &Opt) -> f32 {
+    let r = args.escape_radius();
+    r * r
+}
+
+fn escaped(r: f32, (a, b): Complex) -> bool {
+    a * a + b * b > r
+}
+
+/// The Mandelbrot set, or for other powers the multibrot sets
+pub struct Mandelbrot {
+    power: Power,
+    escape: f32,
+}
+
+impl Mandelbrot {
+    pub fn new(args: &Opt) -> Self {
+        Self {
+            power: Power::new(args.power),
+            escape: escape_radius(args),
+        }
+    }
+}
+
+impl Formula for Mandelbrot {
+    fn step(&self, z: Complex, (x, y): Complex) -> Complex {
+        let (a, b) = self.power.apply(z);
+        (a + x, b + y)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        escaped(self.escape, z)
+    }
+
+    /// Whether c lies in the main cardioid or period-2 bulb of the
+    /// Mandelbrot set, where orbits never escape
+    fn in_interior(&self, (x, y): Complex) -> bool {
+        if !matches!(self.power, Power::Square) {
+            return false;
+        }
+
+        let y2 = y * y;
+
+        let q = (x - 0.25) * (x - 0.25) + y2;
+        let cardioid = q * (q + (x - 0.25)) <= y2 / 4.;
+        let bulb = (x + 1.) * (x + 1.) + y2 <= 1. / 16.;
+
+        cardioid || bulb
+    }
+}
+
+pub struct BurningShip {
+    power: Power,
+    escape: f32,
+}
+
+impl BurningShip {
+    pub fn new(args: &Opt) -> Self {
+        Self {
+            power: Power::new(args.power),
+            escape: escape_radius(args),
+        }
+    }
+}
+
+impl Formula for BurningShip {
+    fn step(&self, (a, b): Complex, (x, y): Complex) -> Complex {
+        let (a, b) = self.power.apply((a.abs(), b.abs()));
+        (a + x, b + y)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        escaped(self.escape, z)
+    }
+}
+
+pub struct Tricorn {
+    power: Power,
+    escape: f32,
+}
+
+impl Tricorn {
+    pub fn new(args: &Opt) -> Self {
+        Self {
+            power: Power::new(args.power),
+            escape: escape_radius(args),
+        }
+    }
+}
+
+impl Formula for Tricorn {
+    fn step(&self, (a, b): Complex, (x, y): Complex) -> Complex {
+        let (a, b) = self.power.apply((a, -b));
+        (a + x, b + y)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        escaped(self.escape, z)
+    }
+}
+
+pub struct Celtic {
+    power: Power,
+    escape: f32,
+}
+
+impl Celtic {
+    pub fn new(args: &Opt) -> Self {
+        Self {
+            power: Power::new(args.power),
+            escape: escape_radius(args),
+        }
+    }
+}
+
+impl Formula for Celtic {
+    fn step(&self, z: Complex, (x, y): Complex) -> Complex {
+        let (a, b) = self.power.apply(z);
+        (a.abs() + x, b + y)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        escaped(self.escape, z)
+    }
+}
+
+pub struct Perpendicular {
+    power: Power,
+    escape: f32,
+}
+
+impl Perpendicular {
+    pub fn new(args: &Opt) -> Self {
+        Self {
+            power: Power::new(args.power),
+            escape: escape_radius(args),
+        }
+    }
+}
+
+impl Formula for Perpendicular {
+    fn step(&self, (a, b): Complex, (x, y): Complex) -> Complex {
+        let (a, b) = self.power.apply((a.abs(), -b));
+        (a + x, b + y)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        escaped(self.escape, z)
+    }
+}
+
+/// The Julia set of a formula for a fixed c, where sample points are the
+/// starting point of the orbit instead
+pub struct Julia<F> {
+    formula: F,
+    c: Complex,
+}
+
+impl<F: Formula> Julia<F> {
+    pub fn new(formula: F, c: Complex) -> Self {
+        Self { formula, c }
+    }
+}
+
+impl<F: Formula> Formula for Julia<F> {
+    fn init(&self, z: Complex) -> Complex {
+        z
+    }
+
+    fn step(&self, z: Complex, _: Complex) -> Complex {
+        self.formula.step(z, self.c)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        self.formula.escaped(z)
+    }
+}
@@ -0,0 +1,102 @@
+use crate::histogram::Histogram;
+use anyhow::{bail, Result};
+use exr::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Check that the extension of `path` is one [`write_hdr`] supports
+pub fn check_path(path: &Path) -> Result<()> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("exr" | "hdr") => Ok(()),
+        _ => bail!("HDR output must end in .exr or .hdr"),
+    }
+}
+
+/// Write histograms as a floating point image, multiplying each count by
+/// `scale`. The format follows the extension of `path`: OpenEXR (`.exr`)
+/// stores one or three channels, while Radiance (`.hdr`) is always RGB,
+/// so single channels are written as gray
+pub fn write_hdr(path: &Path, images: &[Histogram], scale: f32) -> Result<()> {
+    let (width, height) = (images[0].width(), images[0].height());
+
+    let channels: Vec<Vec<f32>> = images
+        .iter()
+        .map(|image| image.bins().iter().map(|&i| i as f32 * scale).collect())
+        .collect();
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("exr") => write_exr(path, &channels, width, height),
+        Some("hdr") => write_radiance(path, &channels, width, height),
+        _ => bail!("HDR output must end in .exr or .hdr"),
+    }
+}
+
+fn write_exr(path: &Path, channels: &[Vec<f32>], width: usize, height: usize) -> Result<()> {
+    let names: &[&str] = match channels.len() {
+        1 => &["Y"],
+        _ => &["R", "G", "B"],
+    };
+
+    // Missing color channels are left black
+    let channels: SmallVec<_> = names
+        .iter()
+        .enumerate()
+        .map(|(c, &name)| {
+            let data = channels
+                .get(c)
+                .cloned()
+                .unwrap_or_else(|| vec![0.; width * height]);
+            AnyChannel::new(name, FlatSamples::F32(data))
+        })
+        .collect();
+
+    let layer = Layer::new(
+        (width, height),
+        LayerAttributes::named("buddha"),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    );
+
+    Image::from_layer(layer).write().to_file(path)?;
+    Ok(())
+}
+
+/// Write uncompressed RGBE scanlines
+fn write_radiance(path: &Path, channels: &[Vec<f32>], width: usize, height: usize) -> Result<()> {
+    let file = File::create(path)?;
+    let mut w = BufWriter::new(file);
+
+    writeln!(w, "#?RADIANCE")?;
+    writeln!(w, "FORMAT=32-bit_rle_rgbe")?;
+    writeln!(w)?;
+    writeln!(w, "-Y {} +X {}", height, width)?;
+
+    let sample = |c: usize, idx: usize| match channels {
+        [gray] => gray[idx],
+        _ => channels.get(c).map_or(0., |ch| ch[idx]),
+    };
+
+    for idx in 0..width * height {
+        let rgb = [sample(0, idx), sample(1, idx), sample(2, idx)];
+        w.write_all(&rgbe(rgb))?;
+    }
+
+    w.flush()?;
+    Ok(())
+}
+
+/// Encode a color as shared-exponent RGBE
+fn rgbe([r, g, b]: [f32; 3]) -> [u8; 4] {
+    let v = r.max(g).max(b);
+    if v < 1e-32 {
+        return [0; 4];
+    }
+
+    // v = m * 2^e with m in [0.5, 1)
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256. / 2_f32.powi(e);
+    let byte = |x: f32| (x * scale).clamp(0., 255.) as u8;
+
+    [byte(r), byte(g), byte(b), (e + 128) as u8]
+}
@@ -0,0 +1,366 @@
+//! Raw histogram files, so that renders can be recolored without resampling.
+//!
+//! A file consists of a text header of `key value` lines terminated by an
+//! empty line, followed by the bin counts of every channel in order as
+//! little-endian `u64`s.
+use crate::histogram::{Count, Histogram};
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+const MAGIC: &str = "buddha-histogram 1";
+
+/// Parameters a histogram was rendered with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub width: usize,
+    pub height: usize,
+    pub center_x: f32,
+    pub center_y: f32,
+    pub scale: f32,
+    pub disc: f32,
+    /// Name of the iterated formula
+    pub formula: String,
+    /// Step limit of each channel
+    pub steps: Vec<usize>,
+    /// Whether each channel records bounded rather than escaping orbits
+    pub bounded: Vec<bool>,
+    /// Escaping orbits with fewer steps than this were discarded
+    pub min_steps: usize,
+    /// Counts recorded per orbit point, so that samplers with fractional
+    /// weights keep their precision
+    pub unit: u32,
+    /// Spectral index and palette stops, if orbits were colored by length
+    pub spectral: Option<String>,
+    /// Number of sample points drawn
+    pub samples: u64,
+    /// First index of quasi-random sequences not yet drawn from, so that
+    /// resumed runs don't repeat points
+    pub sequence: u64,
+}
+
+impl Header {
+    /// Check that histograms with these headers sample the same distribution,
+    /// and so may be merged
+    pub fn check_compatible(&self, other: &Header) -> Result<()> {
+        if (self.width, self.height) != (other.width, other.height) {
+            bail!(
+                "Dimensions differ: {}x{} vs {}x{}",
+                self.width,
+                self.height,
+                other.width,
+                other.height
+            );
+        }
+
+        let viewport = |h: &Header| (h.center_x, h.center_y, h.scale, h.disc);
+        if viewport(self) != viewport(other) {
+            bail!(
+                "Viewports differ: {:?} vs {:?}",
+                viewport(self),
+                viewport(other)
+            );
+        }
+
+        if self.formula != other.formula {
+            bail!("Formulas differ: {} vs {}", self.formula, other.formula);
+        }
+
+        if self.steps != other.steps {
+            bail!("Step limits differ: {:?} vs {:?}", self.steps, other.steps);
+        }
+
+        if self.bounded != other.bounded {
+            bail!("Bounded channels differ");
+        }
+
+        if self.unit != other.unit {
+            bail!("Weight units differ: {} vs {}", self.unit, other.unit);
+        }
+
+        if self.spectral != other.spectral {
+            bail!("Spectral colorings differ");
+        }
+
+        if self.min_steps != other.min_steps {
+            bail!(
+                "Minimum steps differ: {} vs {}",
+                self.min_steps,
+                other.min_steps
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// A header along with one histogram per channel
+#[derive(Debug, Clone)]
+pub struct HistogramFile {
+    pub header: Header,
+    pub channels: Vec<Histogram>,
+}
+
+impl HistogramFile {
+    /// Sum the counts and samples of another compatible histogram into this one
+    pub fn merge(&mut self, other: &HistogramFile) -> Result<()> {
+        self.header.check_compatible(&other.header)?;
+
+        for (channel, other) in self.channels.iter_mut().zip(&other.channels) {
+            channel.merge(other);
+        }
+        self.header.samples += other.header.samples;
+        self.header.sequence = self.header.sequence.max(other.header.sequence);
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut w = BufWriter::new(file);
+
+        let h = &self.header;
+        writeln!(w, "{}", MAGIC)?;
+        writeln!(w, "width {}", h.width)?;
+        writeln!(w, "height {}", h.height)?;
+        writeln!(w, "center_x {}", h.center_x)?;
+        writeln!(w, "center_y {}", h.center_y)?;
+        writeln!(w, "scale {}", h.scale)?;
+        writeln!(w, "disc {}", h.disc)?;
+        writeln!(w, "formula {}", h.formula)?;
+        let steps: Vec<String> = h.steps.iter().map(|s| s.to_string()).collect();
+        writeln!(w, "steps {}", steps.join(" "))?;
+        let bounded: Vec<&str> = h
+            .bounded
+            .iter()
+            .map(|&b| if b { "1" } else { "0" })
+            .collect();
+        writeln!(w, "bounded {}", bounded.join(" "))?;
+        writeln!(w, "min_steps {}", h.min_steps)?;
+        writeln!(w, "unit {}", h.unit)?;
+        if let Some(spectral) = &h.spectral {
+            writeln!(w, "spectral {}", spectral)?;
+        }
+        writeln!(w, "samples {}", h.samples)?;
+        writeln!(w, "sequence {}", h.sequence)?;
+        writeln!(w)?;
+
+        for channel in &self.channels {
+            for &count in channel.bins() {
+                // A no-op with the `wide` feature, where counts are already u64
+                #[allow(clippy::useless_conversion)]
+                w.write_all(&u64::from(count).to_le_bytes())?;
+            }
+        }
+
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let file_len = file.metadata()?.len();
+        let mut r = BufReader::new(file);
+        let header = read_header(&mut r)
+            .with_context(|| format!("Invalid histogram header in {}", path.display()))?;
+
+        // Check the header against the data actually present before
+        // allocating anything it asks for
+        let len = header
+            .width
+            .checked_mul(header.height)
+            .with_context(|| format!("Histogram dimensions overflow in {}", path.display()))?;
+        let data_len = (len as u64)
+            .checked_mul(header.steps.len() as u64)
+            .and_then(|n| n.checked_mul(8));
+        let remaining = file_len.saturating_sub(r.stream_position()?);
+        if data_len.is_none_or(|n| n > remaining) {
+            bail!("Truncated histogram data in {}", path.display());
+        }
+
+        let mut buf = [0; 8];
+        let mut channels = Vec::with_capacity(header.steps.len());
+        for _ in &header.steps {
+            let mut bins = Vec::with_capacity(len);
+            for _ in 0..len {
+                r.read_exact(&mut buf)
+                    .with_context(|| format!("Truncated histogram data in {}", path.display()))?;
+                let count = Count::try_from(u64::from_le_bytes(buf)).context(
+                    "Bin count exceeds the counter width; rebuild with the `wide` feature",
+                )?;
+                bins.push(count);
+            }
+            channels.push(Histogram::from_bins(header.width, header.height, bins));
+        }
+
+        Ok(Self { header, channels })
+    }
+}
+
+fn read_header(r: &mut impl BufRead) -> Result<Header> {
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    if line.trim_end() != MAGIC {
+        bail!("Not a histogram file");
+    }
+
+    let (mut width, mut height) = (None, None);
+    let (mut center_x, mut center_y, mut scale, mut disc) = (None, None, None, None);
+    let (mut formula, mut steps, mut bounded, mut samples) = (None, None, None, None);
+    let (mut min_steps, mut unit, mut sequence) = (0, 1, 0);
+    let mut spectral = None;
+
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            bail!("Unexpected end of header");
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        match key {
+            "width" => width = Some(value.parse()?),
+            "height" => height = Some(value.parse()?),
+            "center_x" => center_x = Some(value.parse()?),
+            "center_y" => center_y = Some(value.parse()?),
+            "scale" => scale = Some(value.parse()?),
+            "disc" => disc = Some(value.parse()?),
+            "formula" => formula = Some(value.to_string()),
+            "steps" => {
+                steps = Some(
+                    value
+                        .split_whitespace()
+                        .map(|s| s.parse())
+                        .collect::<Result<Vec<usize>, _>>()?,
+                )
+            }
+            "bounded" => {
+                bounded = Some(
+                    value
+                        .split_whitespace()
+                        .map(|s| Ok(s.parse::<u8>()? != 0))
+                        .collect::<Result<Vec<bool>>>()?,
+                )
+            }
+            "min_steps" => min_steps = value.parse()?,
+            "unit" => unit = value.parse()?,
+            "spectral" => spectral = Some(value.to_string()),
+            "samples" => samples = Some(value.parse()?),
+            "sequence" => sequence = value.parse()?,
+            _ => bail!("Unknown header field {:?}", key),
+        }
+    }
+
+    let steps: Vec<usize> = steps.context("Missing steps")?;
+
+    // Files from before bounded channels only recorded escaping orbits
+    let bounded = bounded.unwrap_or_else(|| vec![false; steps.len()]);
+    if bounded.len() != steps.len() {
+        bail!("Expected {} bounded flags", steps.len());
+    }
+
+    Ok(Header {
+        width: width.context("Missing width")?,
+        height: height.context("Missing height")?,
+        center_x: center_x.context("Missing center_x")?,
+        center_y: center_y.context("Missing center_y")?,
+        scale: scale.context("Missing scale")?,
+        disc: disc.context("Missing disc")?,
+        formula: formula.context("Missing formula")?,
+        steps,
+        bounded,
+        min_steps,
+        unit,
+        spectral,
+        samples: samples.context("Missing samples")?,
+        sequence,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Path in the temporary directory unique to this test run
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("buddha-{}-{}.hist", std::process::id(), name))
+    }
+
+    fn sample_file() -> HistogramFile {
+        let header = Header {
+            width: 3,
+            height: 2,
+            center_x: -0.5,
+            center_y: 0.25,
+            scale: 1.5,
+            disc: 3.,
+            formula: "mandelbrot".to_string(),
+            steps: vec![100, 1000],
+            bounded: vec![false, true],
+            min_steps: 4,
+            unit: 64,
+            spectral: Some("log 0 0 0 0, 1 1 1 1".to_string()),
+            samples: 12345,
+            sequence: 678,
+        };
+        let channels = vec![
+            Histogram::from_bins(3, 2, vec![0, 1, 2, 3, 4, 5]),
+            Histogram::from_bins(3, 2, vec![Count::MAX, 0, 7, 0, 9, 1]),
+        ];
+        HistogramFile { header, channels }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = temp_path("round-trip");
+        let file = sample_file();
+        file.save(&path).unwrap();
+        let loaded = HistogramFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.header, file.header);
+        assert_eq!(loaded.channels, file.channels);
+    }
+
+    #[test]
+    fn truncated_data_is_rejected() {
+        let path = temp_path("truncated");
+        sample_file().save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        let err = HistogramFile::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(err.to_string().starts_with("Truncated histogram data"));
+    }
+
+    #[test]
+    fn oversized_headers_are_rejected() {
+        let load_header = |name: &str, width: usize, height: usize| {
+            let path = temp_path(name);
+            let mut file = sample_file();
+            file.header.width = width;
+            file.header.height = height;
+            file.save(&path).unwrap();
+            let err = HistogramFile::load(&path).unwrap_err();
+            std::fs::remove_file(&path).unwrap();
+            err.to_string()
+        };
+
+        // More bins than the file holds, without allocating them
+        let err = load_header("oversized", 1 << 20, 1 << 20);
+        assert!(err.starts_with("Truncated histogram data"), "{}", err);
+
+        let err = load_header("overflow", usize::MAX, 2);
+        assert!(err.starts_with("Histogram dimensions overflow"), "{}", err);
+    }
+}
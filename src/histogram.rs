@@ -0,0 +1,76 @@
+/// Bin counter type. Enable the `wide` feature for 64-bit counters
+/// when a single bin may exceed `u32::MAX` samples
+#[cfg(not(feature = "wide"))]
+pub type Count = u32;
+#[cfg(feature = "wide")]
+pub type Count = u64;
+
+/// Accumulated orbit density for a single channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    width: usize,
+    height: usize,
+    bins: Vec<Count>,
+}
+
+impl Histogram {
+    /// Create an empty histogram of the given dimensions
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            bins: vec![0; width * height],
+        }
+    }
+
+    /// Create a histogram from existing bin counts in row-major order
+    pub fn from_bins(width: usize, height: usize, bins: Vec<Count>) -> Self {
+        assert_eq!(bins.len(), width * height, "Bin count mismatch");
+        Self {
+            width,
+            height,
+            bins,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Bin counts in row-major order
+    pub fn bins(&self) -> &[Count] {
+        &self.bins
+    }
+
+    /// Number of bins which reached the largest count, where further hits
+    /// were clipped
+    pub fn saturated(&self) -> usize {
+        self.bins.iter().filter(|&&c| c == Count::MAX).count()
+    }
+
+    /// Record a hit of the given weight at the given bin index, saturating
+    /// at the largest count
+    #[inline]
+    pub fn add(&mut self, idx: usize, weight: Count) {
+        let bin = &mut self.bins[idx];
+        *bin = bin.saturating_add(weight);
+    }
+
+    /// Add the counts of another histogram of the same dimensions into this one
+    pub fn merge(&mut self, other: &Histogram) {
+        assert_eq!(
+            (self.width, self.height),
+            (other.width, other.height),
+            "Histogram dimensions differ"
+        );
+
+        self.bins
+            .iter_mut()
+            .zip(&other.bins)
+            .for_each(|(o, i)| *o = o.saturating_add(*i));
+    }
+}
@@ -1,14 +1,153 @@
+mod expr;
+mod formula;
+mod hdr;
+mod histfile;
+mod histogram;
+mod output;
+mod palette;
+mod sampler;
+mod tonemap;
+mod worker;
+
 // For reading and opening files
-use anyhow::Result;
-use rand::distributions::Uniform;
+use anyhow::{bail, Context, Result};
+use expr::Expr;
+use formula::{with_formula, FormulaKind};
+use histfile::{Header, HistogramFile};
+use histogram::{Count, Histogram};
+use output::OutputOpt;
+use palette::Palette;
 use rand::prelude::*;
-use std::fs::File;
-use std::io::BufWriter;
+use rand::rngs::SmallRng;
+use sampler::{ImportanceMap, SamplerInit, SamplerKind};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread::{available_parallelism, JoinHandle};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use worker::{worker_thread, Stats, Tracer, SPECTRAL_WEIGHT};
+
+/// A single output channel, recording orbits which escape within `steps`,
+/// or for bounded channels, those which don't
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    /// Max steps for an orbit to count towards this channel
+    steps: usize,
+    /// Divide bin counts by this number for image output
+    div: Count,
+    /// Record orbits which never escape (the anti-Buddhabrot) instead
+    bounded: bool,
+}
+
+impl Channel {
+    /// Whether an orbit of `len` points is recorded in this channel, where
+    /// escaping orbits shorter than `min_steps` are discarded
+    fn records(&self, len: usize, min_steps: usize) -> bool {
+        if self.bounded {
+            len >= self.steps
+        } else {
+            len >= min_steps && len < self.steps
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = anyhow::Error;
+
+    /// Parse a channel from `steps` or `steps:div`, prefixed with `anti:` for
+    /// bounded channels
+    fn from_str(s: &str) -> Result<Self> {
+        let (bounded, s) = match s.strip_prefix("anti:") {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+
+        let (steps, div) = match s.split_once(':') {
+            Some((steps, div)) => (steps, Some(div)),
+            None => (s, None),
+        };
+
+        let steps = steps.trim().parse()?;
+        let div = div.map(|d| d.trim().parse()).transpose()?;
+        if steps == 0 {
+            bail!("Channel step limit must be nonzero");
+        }
+        if div == Some(0) {
+            bail!("Channel divisor must be nonzero");
+        }
+
+        Ok(Self {
+            steps,
+            div: div.unwrap_or(0),
+            bounded,
+        })
+    }
+}
+
+/// Which orbits are recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Orbits which escape, the Buddhabrot
+    Buddha,
+    /// Orbits which never escape, the anti-Buddhabrot
+    Anti,
+    /// Both, each into its own channel
+    Both,
+}
 
-type Image = Vec<u16>;
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "buddha" => Self::Buddha,
+            "anti" => Self::Anti,
+            "both" => Self::Both,
+            _ => bail!("Unknown mode {:?}, expected buddha, anti or both", s),
+        })
+    }
+}
+
+/// How orbit lengths index the spectral palette
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpectralIndex {
+    /// Logarithm of the length relative to the step limit
+    Log,
+    /// Length relative to the step limit
+    Linear,
+}
+
+impl FromStr for SpectralIndex {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "log" => Self::Log,
+            "linear" => Self::Linear,
+            _ => bail!("Unknown spectral index {:?}, expected log or linear", s),
+        })
+    }
+}
+
+impl SpectralIndex {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Log => "log",
+            Self::Linear => "linear",
+        }
+    }
+
+    /// Position in the palette of an orbit with `len` points
+    fn position(self, len: usize, steps: usize) -> f32 {
+        match self {
+            Self::Log => (len.max(1) as f32).ln() / (steps.max(2) as f32).ln(),
+            Self::Linear => len as f32 / steps as f32,
+        }
+    }
+}
 
 #[derive(Debug, Clone, StructOpt)]
 struct Opt {
@@ -36,14 +175,37 @@ struct Opt {
     #[structopt(long, default_value = "1.0")]
     scale: f32,
 
-    /// Cutoff disc radius. Optimally 2.0, 
+    /// Cutoff disc radius. Optimally 2.0,
     /// but default to 3.0 for presentation purposes
     #[structopt(long, default_value = "3.0")]
     disc: f32,
 
-    /// Divide all bin counts by this number for image output
-    #[structopt(short, long, default_value = "2")]
-    div: u16,
+    /// Iterated formula: mandelbrot, burning-ship, tricorn (or mandelbar),
+    /// celtic or perpendicular
+    #[structopt(long, default_value = "mandelbrot")]
+    formula: FormulaKind,
+
+    /// Custom formula for the next point of the orbit, such as
+    /// `z^2 + c + 0.3*conj(z)`, in place of --formula. It may use z, c, i,
+    /// pi, e, + - * / ^ and the functions pow, conj, abs, re, im, arg, sqrt,
+    /// exp, log, sin, cos, tan, sinh, cosh and tanh. Orbits start at z = 0
+    /// and escape when they leave the cutoff disc
+    #[structopt(long, conflicts_with = "formula")]
+    expr: Option<Expr>,
+
+    /// Exponent d of the iterated z^d + c, any real number above 1. The
+    /// default 2 renders the Mandelbrot set, others its multibrot relatives
+    #[structopt(long, default_value = "2")]
+    power: f32,
+
+    /// Real part of c for rendering a Julia set, where c is fixed and the
+    /// sampler draws starting points z0 from the disc instead
+    #[structopt(long, requires = "julia-ci", allow_hyphen_values = true)]
+    julia_cr: Option<f32>,
+
+    /// Imaginary part of c for rendering a Julia set
+    #[structopt(long, requires = "julia-cr", allow_hyphen_values = true)]
+    julia_ci: Option<f32>,
 
     /// Total iterations
     #[structopt(short, long, default_value = "10000000")]
@@ -52,130 +214,683 @@ struct Opt {
     /// Max steps per iteration
     #[structopt(short, long, default_value = "1500")]
     steps: usize,
+
+    /// Discard escaping orbits with fewer steps than this
+    #[structopt(long, default_value = "0")]
+    min_steps: usize,
+
+    /// Skip samples inside the main cardioid and period-2 bulb without
+    /// iterating them. On by default unless recording bounded orbits. Only
+    /// applies to the Mandelbrot formula with --power 2
+    #[structopt(long, conflicts_with = "keep-interior")]
+    skip_interior: bool,
+
+    /// Iterate samples inside the main cardioid and period-2 bulb
+    #[structopt(long)]
+    keep_interior: bool,
+
+    /// How sample points are chosen: uniform, the quasi-random halton, sobol
+    /// or r2 sequences, or for zoomed in views where few uniform samples
+    /// reach the frame, metropolis or importance
+    #[structopt(long, default_value = "uniform")]
+    sampler: SamplerKind,
+
+    /// Size of Metropolis mutations, relative to --scale
+    #[structopt(long, default_value = "0.05")]
+    mh_mutation: f32,
+
+    /// Probability of Metropolis proposals drawn uniformly from the disc
+    /// instead of mutating the current point
+    #[structopt(long, default_value = "0.1")]
+    mh_large: f32,
+
+    /// Uniform samples per worker used to normalize Metropolis sampling
+    #[structopt(long, default_value = "100000")]
+    mh_bootstrap: usize,
+
+    /// Counts recorded per orbit point by the metropolis and importance
+    /// samplers, whose fractional weights are rounded to this precision
+    #[structopt(long, default_value = "64")]
+    weight_unit: u32,
+
+    /// Exploit the symmetry of the set about the real axis: sample only the
+    /// upper half of the disc and record each orbit along with its mirror
+    /// image, so every sample counts twice
+    #[structopt(long)]
+    symmetric: bool,
+
+    /// Cells along each side of the importance map over the disc
+    #[structopt(long, default_value = "128")]
+    importance_grid: usize,
+
+    /// Pre-pass samples per importance map cell
+    #[structopt(long, default_value = "16")]
+    importance_samples: usize,
+
+    /// Sampling density given to every importance map cell, relative to
+    /// the mean, so regions the pre-pass missed are still sampled
+    #[structopt(long, default_value = "0.05")]
+    importance_floor: f32,
+
+    /// Stop iterating orbits which return within this distance of an
+    /// earlier point, as they are periodic and never escape (e.g. 1e-6).
+    /// Can't be used with bounded channels, which need every point
+    #[structopt(long)]
+    periodicity: Option<f32>,
+
+    /// Color channel as `steps` or `steps:div`, given up to three times
+    /// for red, green and blue. Orbits are recorded into every channel
+    /// whose step limit they escape within. Channels without a divisor
+    /// use --div. Defaults to a single grayscale channel using --steps.
+    /// Prefix with `anti:` to record orbits which don't escape instead
+    #[structopt(long = "channel", number_of_values = 1)]
+    channels: Vec<Channel>,
+
+    /// Color each orbit escaping within --steps by its length through this
+    /// palette (a built-in name or gradient file), so a single render yields
+    /// red, green and blue channels. Each hit adds up to 255 to a channel,
+    /// and --div applies per full intensity hit
+    #[structopt(long, conflicts_with = "channels")]
+    spectral: Option<Palette>,
+
+    /// How orbit lengths index the spectral palette: log or linear
+    #[structopt(long, default_value = "log")]
+    spectral_index: SpectralIndex,
+
+    /// Which orbits to record: buddha (escaping), anti (never escaping
+    /// within the step limit) or both, where every channel is followed by
+    /// a channel of the orbits it doesn't escape within
+    #[structopt(long, default_value = "buddha")]
+    mode: Mode,
+
+    /// Random seed. A given seed, thread count and iteration count always
+    /// produce the same image. Chosen randomly if not specified. Also shifts
+    /// the quasi-random samplers, so resume them with the same seed
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Number of worker threads. Defaults to the available parallelism
+    #[structopt(long)]
+    threads: Option<usize>,
+
+    /// Also save the raw histogram to this path, for later recoloring
+    #[structopt(long)]
+    save_histogram: Option<PathBuf>,
+
+    /// Skip writing the image, e.g. when only saving the histogram
+    #[structopt(long)]
+    no_png: bool,
+
+    /// Periodically save the accumulated histogram and progress to this path
+    #[structopt(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Seconds between checkpoints
+    #[structopt(long, default_value = "300")]
+    checkpoint_interval: u64,
+
+    /// Continue from the checkpoint towards --iters total samples
+    #[structopt(long, requires = "checkpoint")]
+    resume: bool,
+
+    #[structopt(flatten)]
+    output: OutputOpt,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum Command {
+    /// Color a saved histogram without resampling
+    Colorize(ColorizeOpt),
+    /// Sum saved histograms of the same view into a single histogram
+    Merge(MergeOpt),
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct MergeOpt {
+    /// Merged histogram path
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// Histogram files to merge
+    #[structopt(required = true)]
+    inputs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct ColorizeOpt {
+    /// Histogram file to color
+    input: PathBuf,
+
+    /// Output path
+    #[structopt(default_value = "out.png")]
+    out_path: PathBuf,
+
+    /// Channel to color as `steps` or `steps:div`, selecting the saved channel
+    /// with that step limit. Prefix with `anti:` to select a bounded channel.
+    /// May be given up to three times for red, green and blue. Defaults to
+    /// all saved channels
+    #[structopt(long = "channel", number_of_values = 1)]
+    channels: Vec<Channel>,
+
+    #[structopt(flatten)]
+    output: OutputOpt,
 }
 
-fn mandelbrot(x: f32, y: f32, disc: f32) -> impl Iterator<Item = (f32, f32)> {
-    let (mut a, mut b) = (0., 0.);
-    let r = disc * disc;
-    std::iter::from_fn(move || {
-        let tmp = a * a - b * b + x;
-        b = 2. * a * b + y;
-        a = tmp;
+impl Opt {
+    /// Output channels, falling back to a single channel from --steps and --div
+    fn channels(&self) -> Vec<Channel> {
+        let channel = Channel {
+            steps: self.steps,
+            div: self.output.div,
+            bounded: false,
+        };
+
+        if self.spectral.is_some() {
+            return vec![channel; 3];
+        }
 
-        if a * a + b * b > r {
-            None
+        let channels = if self.channels.is_empty() {
+            vec![channel]
         } else {
-            Some((a, b))
+            with_default_div(&self.channels, self.output.div)
+        };
+
+        match self.mode {
+            Mode::Buddha => channels,
+            Mode::Anti => channels
+                .into_iter()
+                .map(|c| Channel { bounded: true, ..c })
+                .collect(),
+            Mode::Both => channels
+                .into_iter()
+                .flat_map(|c| [c, Channel { bounded: true, ..c }])
+                .collect(),
         }
-    })
-}
+    }
 
-fn worker_thread(args: Opt, iters: usize) -> Image {
-    let mut image_data = vec![0_u16; args.width * args.height];
+    /// Whether to skip samples inside the main cardioid and period-2 bulb.
+    /// They never escape, so only bounded channels need them
+    fn skip_interior(&self, channels: &[Channel]) -> bool {
+        let default =
+            self.has_interior_test() && !(self.keep_interior || channels.iter().any(|c| c.bounded));
+        self.skip_interior || default
+    }
 
-    // Image framing
-    let scale = |x: f32| ((x / args.scale) + 1.) / 2.;
-    let aspect = args.width as f32 / args.height as f32;
+    /// Whether the interior test is valid for the iterated formula
+    fn has_interior_test(&self) -> bool {
+        self.expr.is_none()
+            && self.julia().is_none()
+            && self.formula == FormulaKind::Mandelbrot
+            && self.power == 2.
+    }
 
-    // Save all steps taken
-    let mut steps = Vec::with_capacity(args.steps);
+    /// Whether orbits are symmetric about the real axis
+    fn symmetric_formula(&self) -> bool {
+        let formula = match &self.expr {
+            Some(expr) => expr.symmetric(),
+            None => self.formula.symmetric(),
+        };
 
-    for (idx, (x, y)) in disc(args.disc).take(iters).enumerate() {
-        steps.clear();
-        steps.extend(mandelbrot(x, y, args.disc).take(args.steps));
+        // Julia sets are only mirrored for real c
+        formula && self.julia_ci.unwrap_or(0.) == 0.
+    }
 
-        // Print progress
-        if idx % 100_000 == 0 {
-            println!("{}/{} ({}%)", idx, iters, idx * 100 / iters);
+    /// Radius beyond which orbits are known to escape. Once |z| exceeds
+    /// both |c| and 2^(1/(d-1)) it grows without bound, so this is the
+    /// cutoff disc unless another power is small enough to need more. At
+    /// the default power the disc is used as given, even below 2
+    fn escape_radius(&self) -> f32 {
+        let c = self.julia().map_or(0., |(a, b)| (a * a + b * b).sqrt());
+        let radius = self.disc.max(c);
+        if self.power == 2. {
+            radius
+        } else {
+            radius.max(2f32.powf((self.power - 1.).recip()))
         }
+    }
 
-        // If the function diverged...
-        if steps.len() != args.steps {
-            for (x, y) in steps.drain(..) {
-                // Find position in image
-                let x = scale((x - args.center_x) / aspect) * args.width as f32;
-                let y = scale(y - args.center_y) * args.height as f32;
+    /// The fixed c of Julia set renders
+    fn julia(&self) -> Option<(f32, f32)> {
+        self.julia_cr.zip(self.julia_ci)
+    }
 
-                // Bounds check
-                let bound_x = x >= 0. && x < args.width as f32;
-                let bound_y = y >= 0. && y < args.height as f32;
+    /// Name of the iterated formula
+    fn formula_name(&self) -> String {
+        let name = match self.formula {
+            FormulaKind::Mandelbrot if self.power != 2. => "multibrot",
+            kind => kind.name(),
+        };
+
+        let formula = match &self.expr {
+            Some(expr) => format!("custom {}", expr.source()),
+            None if self.power == 2. => name.into(),
+            None => format!("{} {}", name, self.power),
+        };
+
+        match self.julia() {
+            Some((a, b)) => format!("{} julia {} {}", formula, a, b),
+            None => formula,
+        }
+    }
 
-                // Write to image
-                if bound_x && bound_y {
-                    let idx = x as usize + y as usize * args.width;
-                    image_data[idx] = image_data[idx].saturating_add(1);
-                }
-            }
+    /// Counts recorded per orbit point
+    fn unit(&self) -> u32 {
+        let spectral = if self.spectral.is_some() {
+            SPECTRAL_WEIGHT
+        } else {
+            1
+        };
+        self.sample_unit() * spectral
+    }
+
+    /// Counts recorded per orbit point for each unit of sample weight
+    fn sample_unit(&self) -> u32 {
+        match self.sampler {
+            SamplerKind::Metropolis | SamplerKind::Importance => self.weight_unit.max(1),
+            _ => 1,
+        }
+    }
+
+    /// Describe a histogram sampled with these options
+    fn header(&self, channels: &[Channel], samples: u64, sequence: u64) -> Header {
+        Header {
+            width: self.width,
+            height: self.height,
+            center_x: self.center_x,
+            center_y: self.center_y,
+            scale: self.scale,
+            disc: self.disc,
+            formula: self.formula_name(),
+            steps: channels.iter().map(|c| c.steps).collect(),
+            bounded: channels.iter().map(|c| c.bounded).collect(),
+            min_steps: self.min_steps,
+            unit: self.unit(),
+            spectral: self
+                .spectral
+                .as_ref()
+                .map(|p| format!("{} {}", self.spectral_index.name(), p.describe())),
+            samples,
+            sequence,
         }
     }
+}
+
+/// Fill in the divisor of channels which did not specify their own
+fn with_default_div(channels: &[Channel], div: Count) -> Vec<Channel> {
+    channels
+        .iter()
+        .map(|c| Channel {
+            div: if c.div == 0 { div } else { c.div },
+            ..*c
+        })
+        .collect()
+}
 
-    image_data
+/// Divisors of each channel in counts, so that --div applies per orbit
+/// point whatever the weight unit
+fn unit_divs(channels: &[Channel], unit: u32) -> Vec<Count> {
+    channels
+        .iter()
+        .map(|c| c.div.saturating_mul(unit as Count))
+        .collect()
 }
 
 fn main() -> Result<()> {
     let args = Opt::from_args();
 
+    match &args.cmd {
+        Some(Command::Colorize(opt)) => colorize(opt),
+        Some(Command::Merge(opt)) => merge(opt),
+        None => render(&args),
+    }
+}
+
+/// Sample the fractal and write the results
+fn render(args: &Opt) -> Result<()> {
+    if args.spectral.is_some() && args.mode != Mode::Buddha {
+        bail!("Spectral coloring only applies to escaping orbits");
+    }
+
+    let channels = args.channels();
+    if channels.len() > 3 {
+        bail!("At most three channels (red, green, blue) are supported");
+    }
+    args.output.check(channels.len())?;
+
+    if args.power.is_nan() || args.power <= 1. {
+        bail!("Power must be greater than 1, or orbits never escape");
+    }
+
+    if args.skip_interior && !args.has_interior_test() {
+        bail!("The interior test only applies to the Mandelbrot set, with power 2");
+    }
+
+    if args.expr.is_some() && args.power != 2. {
+        bail!("--power doesn't apply to custom formulas, use ^ instead");
+    }
+
+    if args.symmetric && !args.symmetric_formula() {
+        bail!("The formula isn't symmetric about the real axis");
+    }
+
+    if args.periodicity.is_some() && channels.iter().any(|c| c.bounded) {
+        bail!("Periodicity checking skips points bounded channels need");
+    }
+
+    if args.sampler == SamplerKind::Importance {
+        if args.importance_samples == 0 {
+            bail!("The importance map needs at least one sample per cell");
+        }
+        // Cells without density would never be sampled, biasing the image
+        if args.importance_floor.is_nan() || args.importance_floor <= 0. {
+            bail!("The importance floor must be positive, so that every cell is sampled");
+        }
+    }
+
+    if args.sampler == SamplerKind::Metropolis {
+        let mutation = args.mh_mutation * args.scale;
+        if mutation.is_nan() || mutation <= 0. {
+            bail!("Metropolis mutations need a positive size, from --mh-mutation and --scale");
+        }
+        if !(0. ..=1.).contains(&args.mh_large) {
+            bail!("--mh-large is a probability, between 0 and 1");
+        }
+    }
+
+    if let Some(path) = &args.output.hdr {
+        hdr::check_path(path)?;
+    }
+
+    // Pick up where a previous run left off
+    let mut out_images = vec![Histogram::new(args.width, args.height); channels.len()];
+    let mut samples = 0;
+    let mut sequence = 0;
+    if let Some(path) = args.checkpoint.as_ref().filter(|_| args.resume) {
+        let file = HistogramFile::load(path)?;
+        args.header(&channels, 0, 0)
+            .check_compatible(&file.header)
+            .with_context(|| format!("Cannot resume from {}", path.display()))?;
+        samples = file.header.samples;
+        sequence = file.header.sequence;
+        out_images = file.channels;
+        println!("Resuming from {} samples", samples);
+    }
+
     // Divide work
-    let n_workers = available_parallelism().map(|v| v.get()).unwrap_or(1);
-    let iters_per_worker = args.iters / n_workers;
+    let n_workers = args
+        .threads
+        .unwrap_or_else(|| available_parallelism().map(|v| v.get()).unwrap_or(1))
+        .max(1);
+    let remaining = args.iters.saturating_sub(samples as usize);
+    let iters_per_worker = remaining / n_workers;
+
+    // Each worker draws from its own stream, derived from the seed. Resumed
+    // runs mix in the progress so that they don't repeat earlier samples
+    let seed = args.seed.unwrap_or_else(random);
+    println!("Seed: {}", seed);
+    let mut seeder = SmallRng::seed_from_u64(seed ^ samples.wrapping_mul(0x9E3779B97F4A7C15));
+
+    // Quasi-random sequences are shifted by the seed alone, so that resumed
+    // runs continue the same sequence while other seeds draw other points
+    let sequence_offset = {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        (rng.gen(), rng.gen())
+    };
+
+    // Map out where orbits reach the frame before sampling
+    let map = (args.sampler == SamplerKind::Importance).then(|| {
+        let mut rng = SmallRng::from_rng(&mut seeder).expect("Seeding failed");
+        let map = with_formula!(args, formula => {
+            let mut tracer = Tracer::new(args, &channels, formula);
+            ImportanceMap::build(args, &mut tracer, &mut rng)
+        });
+        Arc::new(map)
+    });
+
+    // Stop sampling on Ctrl-C, but still write out what was collected
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        ctrlc::set_handler(move || {
+            if stop.swap(true, Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+            println!("Interrupted, finishing up. Press Ctrl-C again to abort");
+        })?;
+    }
 
     // Spawn workers
-    let workers: Vec<JoinHandle<Image>> = (0..n_workers)
-        .map(|_| {
+    let (tx, rx) = mpsc::channel();
+    let workers: Vec<JoinHandle<()>> = (0..n_workers)
+        .map(|worker| {
             let args = args.clone();
-            std::thread::spawn(move || worker_thread(args, iters_per_worker))
+            let channels = channels.clone();
+            let tx = tx.clone();
+            let stop = stop.clone();
+
+            // Quasi-random sequences are drawn on the square around the disc,
+            // so give each worker room for twice its samples
+            let span = 2 * iters_per_worker as u64;
+            let init = SamplerInit {
+                rng: SmallRng::from_rng(&mut seeder).expect("Seeding failed"),
+                map: map.clone(),
+                sequence_start: sequence + worker as u64 * span + 1,
+                sequence_offset,
+            };
+
+            std::thread::spawn(move || {
+                with_formula!(&args, formula => {
+                    worker_thread(formula, args, channels, iters_per_worker, init, tx, stop)
+                })
+            })
         })
         .collect();
+    drop(tx);
+
+    // Sum images as workers report them
+    let checkpoint_interval = Duration::from_secs(args.checkpoint_interval);
+    let mut last_checkpoint = Instant::now();
+    let mut stats = Stats::default();
+    for batch in rx {
+        for (out_image, image) in out_images.iter_mut().zip(&batch.images) {
+            out_image.merge(image);
+        }
+        samples += batch.stats.samples;
+        sequence = sequence.max(batch.stats.sequence);
+        stats.add(&batch.stats);
+
+        if let Some(path) = &args.checkpoint {
+            if last_checkpoint.elapsed() >= checkpoint_interval {
+                out_images =
+                    save_checkpoint(path, args.header(&channels, samples, sequence), out_images)?;
+                last_checkpoint = Instant::now();
+            }
+        }
+    }
 
-    // Collect results
-    let mut images: Vec<Image> = workers
-        .into_iter()
-        .map(|w| w.join().expect("Worker failed"))
-        .collect();
+    for worker in workers {
+        worker.join().expect("Worker failed");
+    }
 
-    // Sum images
-    let mut out_image = images.pop().unwrap();
-    for image in images {
-        out_image
-            .iter_mut()
-            .zip(image.iter())
-            .for_each(|(o, i)| *o = o.saturating_add(*i));
+    if stop.load(Ordering::Relaxed) {
+        println!("Interrupted after {} of {} samples", samples, args.iters);
     }
 
-    // Determine coloring
-    let out_image: Vec<u8> = out_image
-        .into_iter()
-        .map(|i| (i / args.div).min(u8::MAX as u16) as u8)
-        .collect();
+    if args.skip_interior(&channels) {
+        println!(
+            "Interior test rejected {} of {} samples ({:.1}%)",
+            stats.rejected,
+            stats.samples,
+            stats.rejected as f64 * 100. / stats.samples.max(1) as f64
+        );
+    }
+
+    if args.periodicity.is_some() {
+        println!(
+            "Periodicity check caught {} of {} samples ({:.1}%)",
+            stats.periodic,
+            stats.samples,
+            stats.periodic as f64 * 100. / stats.samples.max(1) as f64
+        );
+    }
+
+    warn_saturated(&out_images);
+
+    if let Some(path) = &args.checkpoint {
+        out_images = save_checkpoint(path, args.header(&channels, samples, sequence), out_images)?;
+    }
+
+    // Save raw counts
+    if let Some(path) = &args.save_histogram {
+        let file = HistogramFile {
+            header: args.header(&channels, samples, sequence),
+            channels: out_images,
+        };
+        file.save(path)?;
+        out_images = file.channels;
+    }
+
+    // Write results. A failed HDR write still leaves the PNG
+    let hdr = match &args.output.hdr {
+        Some(path) => hdr::write_hdr(
+            path,
+            &out_images,
+            args.output.hdr_scale(samples, args.unit()),
+        ),
+        None => Ok(()),
+    };
+
+    if !args.no_png {
+        let divs = unit_divs(&channels, args.unit());
+        output::write_image(&args.out_path, &out_images, &divs, &args.output)?;
+    }
 
-    // Write results
-    write_png(
-        &args.out_path,
-        &out_image,
-        args.width as u32,
-        args.height as u32,
-    )
+    hdr
 }
 
-/// Produce random points on the unit disc with the given radius
-fn disc(radius: f32) -> impl Iterator<Item = (f32, f32)> {
-    let unif = Uniform::new(-radius, radius);
-    unif.sample_iter(rand::thread_rng())
-        .zip(unif.sample_iter(rand::thread_rng()))
-        .filter(move |(x, y)| x * x + y * y < radius * radius)
+/// Warn when bins clipped at the largest count, which loses the brightest
+/// detail of the image
+fn warn_saturated(images: &[Histogram]) {
+    let saturated: usize = images.iter().map(Histogram::saturated).sum();
+    if saturated > 0 {
+        eprintln!(
+            "Warning: {} bins reached the counter limit and were clipped; rebuild with the `wide` feature for 64-bit counters",
+            saturated
+        );
+    }
 }
 
-/// Write a grayscale PNG at the given path
-fn write_png(path: &Path, data: &[u8], width: u32, height: u32) -> Result<()> {
-    let file = File::create(path)?;
-    let ref mut w = BufWriter::new(file);
+/// Atomically replace the checkpoint at `path`, handing the histograms back
+fn save_checkpoint(path: &Path, header: Header, images: Vec<Histogram>) -> Result<Vec<Histogram>> {
+    let file = HistogramFile {
+        header,
+        channels: images,
+    };
 
-    let mut encoder = png::Encoder::new(w, width, height);
-    encoder.set_color(png::ColorType::Grayscale);
-    encoder.set_depth(png::BitDepth::Eight);
+    let tmp = path.with_extension("tmp");
+    file.save(&tmp)?;
+    std::fs::rename(&tmp, path)?;
+    println!("Checkpoint: {} samples", file.header.samples);
 
-    let mut writer = encoder.write_header()?;
-    writer.write_image_data(&data)?;
+    Ok(file.channels)
+}
+
+/// Color a saved histogram
+fn colorize(opt: &ColorizeOpt) -> Result<()> {
+    if let Some(path) = &opt.output.hdr {
+        hdr::check_path(path)?;
+    }
+
+    let file = HistogramFile::load(&opt.input)?;
+
+    // Select the requested channels by step limit, or all of them
+    let channels = if opt.channels.is_empty() {
+        file.header
+            .steps
+            .iter()
+            .zip(&file.header.bounded)
+            .map(|(&steps, &bounded)| Channel {
+                steps,
+                div: opt.output.div,
+                bounded,
+            })
+            .collect()
+    } else {
+        with_default_div(&opt.channels, opt.output.div)
+    };
+
+    if channels.len() > 3 {
+        bail!("At most three channels (red, green, blue) are supported");
+    }
+    opt.output.check(channels.len())?;
+
+    // Without a selection, take every channel in order, as spectral renders
+    // save several with the same step limit
+    if opt.channels.is_empty() {
+        let divs = unit_divs(&channels, file.header.unit);
+        return write_colorized(opt, &file, &file.channels, &divs);
+    }
+
+    let images = channels
+        .iter()
+        .map(|c| {
+            let idx = file
+                .header
+                .steps
+                .iter()
+                .zip(&file.header.bounded)
+                .position(|(&s, &b)| (s, b) == (c.steps, c.bounded))
+                .with_context(|| {
+                    let kind = if c.bounded { "bounded" } else { "escaping" };
+                    format!("No {} channel with step limit {} saved", kind, c.steps)
+                })?;
+            Ok(file.channels[idx].clone())
+        })
+        .collect::<Result<Vec<Histogram>>>()?;
+
+    let divs = unit_divs(&channels, file.header.unit);
+    write_colorized(opt, &file, &images, &divs)
+}
+
+/// Write the HDR and PNG output of colorized channels
+fn write_colorized(
+    opt: &ColorizeOpt,
+    file: &HistogramFile,
+    images: &[Histogram],
+    divs: &[Count],
+) -> Result<()> {
+    let hdr = match &opt.output.hdr {
+        Some(path) => hdr::write_hdr(
+            path,
+            images,
+            opt.output.hdr_scale(file.header.samples, file.header.unit),
+        ),
+        None => Ok(()),
+    };
+
+    output::write_image(&opt.out_path, images, divs, &opt.output)?;
+
+    hdr
+}
+
+/// Sum saved histograms
+fn merge(opt: &MergeOpt) -> Result<()> {
+    let mut merged = HistogramFile::load(&opt.inputs[0])?;
+    for path in &opt.inputs[1..] {
+        let file = HistogramFile::load(path)?;
+        merged
+            .merge(&file)
+            .with_context(|| format!("Cannot merge {}", path.display()))?;
+    }
 
-    Ok(())
+    println!(
+        "Merged {} histograms, {} samples total",
+        opt.inputs.len(),
+        merged.header.samples
+    );
+    warn_saturated(&merged.channels);
+    merged.save(&opt.output)
 }
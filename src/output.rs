@@ -0,0 +1,149 @@
+use crate::histogram::{Count, Histogram};
+use crate::palette::Palette;
+use crate::tonemap::ToneMapOpt;
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Options controlling how histograms are turned into images
+#[derive(Debug, Clone, StructOpt)]
+pub struct OutputOpt {
+    /// Divide all bin counts by this number for image output
+    #[structopt(short, long, default_value = "2")]
+    pub div: Count,
+
+    /// Bits per channel of the PNG output, 8 or 16
+    #[structopt(long, default_value = "8", possible_values = &["8", "16"])]
+    pub depth: u8,
+
+    /// Color single channel renders through a gradient: a built-in palette
+    /// (gray, inferno, magma, viridis, fire) or a gradient file of lines
+    /// holding a position and a `#rrggbb` color
+    #[structopt(long)]
+    pub palette: Option<Palette>,
+
+    /// Also write the raw counts as a floating point HDR image, in OpenEXR
+    /// (.exr) or Radiance (.hdr) format depending on the extension
+    #[structopt(long)]
+    pub hdr: Option<PathBuf>,
+
+    /// Divide HDR output by the number of samples taken, and by the weight
+    /// unit of weighted samplers
+    #[structopt(long)]
+    pub hdr_normalize: bool,
+
+    #[structopt(flatten)]
+    pub tonemap: ToneMapOpt,
+}
+
+impl OutputOpt {
+    /// Check that the divisor is nonzero and that a palette is only given
+    /// for a single channel. Renders call this before sampling, so that
+    /// mistakes don't surface hours later
+    pub fn check(&self, channels: usize) -> Result<()> {
+        if self.div == 0 {
+            bail!("--div must be nonzero");
+        }
+        if self.palette.is_some() && channels != 1 {
+            bail!("Palettes only apply to single channel renders");
+        }
+        Ok(())
+    }
+
+    /// Factor applied to counts in HDR output, given the number of samples
+    /// and counts recorded per orbit point
+    pub fn hdr_scale(&self, samples: u64, unit: u32) -> f32 {
+        if self.hdr_normalize {
+            (samples.max(1) as f64 * unit.max(1) as f64).recip() as f32
+        } else {
+            1.
+        }
+    }
+}
+
+/// Tone map up to three channel histograms and write them to a PNG. Single
+/// channels are written as grayscale unless colored through a palette,
+/// otherwise channels are interleaved as red, green and blue. `divs` holds
+/// the divisor of each channel
+pub fn write_image(
+    path: &Path,
+    images: &[Histogram],
+    divs: &[Count],
+    opt: &OutputOpt,
+) -> Result<()> {
+    let (width, height) = (images[0].width(), images[0].height());
+
+    let (depth, max) = match opt.depth {
+        16 => (png::BitDepth::Sixteen, u16::MAX),
+        _ => (png::BitDepth::Eight, u8::MAX as u16),
+    };
+
+    // Determine coloring
+    let mut levels: Vec<Vec<f32>> = images
+        .iter()
+        .zip(divs)
+        .map(|(image, &div)| opt.tonemap.apply(image, div))
+        .collect();
+
+    opt.check(levels.len())?;
+    if let Some(palette) = &opt.palette {
+        levels = palette.colorize(&levels[0]);
+    }
+
+    let colored: Vec<Vec<u16>> = levels
+        .into_iter()
+        .map(|channel| {
+            channel
+                .into_iter()
+                .map(|v| (v.clamp(0., 1.) * max as f32).round() as u16)
+                .collect()
+        })
+        .collect();
+
+    let (out_image, color) = match colored.as_slice() {
+        [gray] => (gray.clone(), png::ColorType::Grayscale),
+        _ => {
+            let mut rgb = vec![0; width * height * 3];
+            for (c, image) in colored.iter().enumerate() {
+                rgb.iter_mut()
+                    .skip(c)
+                    .step_by(3)
+                    .zip(image)
+                    .for_each(|(o, i)| *o = *i);
+            }
+            (rgb, png::ColorType::Rgb)
+        }
+    };
+
+    // PNG stores 16-bit samples big-endian
+    let data: Vec<u8> = match depth {
+        png::BitDepth::Sixteen => out_image.iter().flat_map(|v| v.to_be_bytes()).collect(),
+        _ => out_image.iter().map(|&v| v as u8).collect(),
+    };
+
+    write_png(path, &data, width as u32, height as u32, color, depth)
+}
+
+/// Write a grayscale or RGB PNG at the given path
+fn write_png(
+    path: &Path,
+    data: &[u8],
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    depth: png::BitDepth,
+) -> Result<()> {
+    let file = File::create(path)?;
+    let w = &mut BufWriter::new(file);
+
+    let mut encoder = png::Encoder::new(w, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use anyhow::{bail, Context, Result};
+use std::str::FromStr;
+
+/// Gradient of colors, mapping intensities in `0.0..=1.0` to RGB
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    /// Color stops, sorted by position from 0 to 1
+    stops: Vec<(f32, [f32; 3])>,
+}
+
+/// Built-in palettes as evenly spaced hex color stops
+const BUILTIN: &[(&str, &[&str])] = &[
+    ("gray", &["000000", "ffffff"]),
+    (
+        "inferno",
+        &[
+            "000004", "1b0c41", "4a0c6b", "781c6d", "a52c60", "cf4446", "ed6925", "fb9b06",
+            "f7d13d", "fcffa4",
+        ],
+    ),
+    (
+        "magma",
+        &[
+            "000004", "180f3d", "440f76", "721f81", "9e2f7f", "cd4071", "f1605d", "fd9668",
+            "feca8d", "fcfdbf",
+        ],
+    ),
+    (
+        "viridis",
+        &[
+            "440154", "472d7b", "3b528b", "2c728e", "21918c", "28ae80", "5ec962", "addc30",
+            "fde725",
+        ],
+    ),
+    (
+        "fire",
+        &["000000", "800000", "ff4000", "ffc000", "ffff80", "ffffff"],
+    ),
+];
+
+impl Palette {
+    /// Create a palette from color stops at arbitrary positions, which are
+    /// rescaled to span `0.0..=1.0`
+    pub fn new(mut stops: Vec<(f32, [f32; 3])>) -> Result<Self> {
+        if stops.is_empty() {
+            bail!("Palette has no color stops");
+        }
+
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (first, last) = (stops[0].0, stops[stops.len() - 1].0);
+        let range = last - first;
+        for (pos, _) in &mut stops {
+            *pos = if range > 0. {
+                (*pos - first) / range
+            } else {
+                0.
+            };
+        }
+
+        Ok(Self { stops })
+    }
+
+    /// Look up a built-in palette by name
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (_, hexes) = BUILTIN.iter().find(|(n, _)| *n == name)?;
+        let last = (hexes.len() - 1).max(1) as f32;
+        let stops = hexes
+            .iter()
+            .enumerate()
+            .map(|(i, hex)| {
+                (
+                    i as f32 / last,
+                    parse_hex(hex).expect("Invalid built-in color"),
+                )
+            })
+            .collect();
+        Self::new(stops).ok()
+    }
+
+    /// Load a gradient file. Each line holds a position followed by either a
+    /// `#rrggbb` color or red, green and blue components from 0 to 1. Blank
+    /// lines and lines starting with `#` are ignored
+    pub fn load(text: &str) -> Result<Self> {
+        let mut stops = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let stop = parse_stop(line).with_context(|| format!("Line {}: {:?}", n + 1, line))?;
+            stops.push(stop);
+        }
+
+        Self::new(stops)
+    }
+
+    /// Describe the color stops on a single line, as comma separated
+    /// positions and red, green and blue components
+    pub fn describe(&self) -> String {
+        let stops: Vec<String> = self
+            .stops
+            .iter()
+            .map(|(pos, [r, g, b])| format!("{} {} {} {}", pos, r, g, b))
+            .collect();
+        stops.join(", ")
+    }
+
+    /// Interpolate the color at `t`, clamped to `0.0..=1.0`
+    pub fn sample(&self, t: f32) -> [f32; 3] {
+        let t = t.clamp(0., 1.);
+        let idx = self.stops.partition_point(|(pos, _)| *pos < t);
+
+        if idx == 0 {
+            return self.stops[0].1;
+        }
+        if idx == self.stops.len() {
+            return self.stops[idx - 1].1;
+        }
+
+        let (p0, c0) = self.stops[idx - 1];
+        let (p1, c1) = self.stops[idx];
+        let f = if p1 > p0 { (t - p0) / (p1 - p0) } else { 1. };
+        [0, 1, 2].map(|c| c0[c] + (c1[c] - c0[c]) * f)
+    }
+
+    /// Map a channel of intensities to red, green and blue channels
+    pub fn colorize(&self, levels: &[f32]) -> Vec<Vec<f32>> {
+        let colors: Vec<[f32; 3]> = levels.iter().map(|&v| self.sample(v)).collect();
+        (0..3)
+            .map(|c| colors.iter().map(|color| color[c]).collect())
+            .collect()
+    }
+}
+
+impl FromStr for Palette {
+    type Err = anyhow::Error;
+
+    /// Parse a built-in palette name, or else a path to a gradient file
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(palette) = Self::builtin(s) {
+            return Ok(palette);
+        }
+
+        let names: Vec<&str> = BUILTIN.iter().map(|(n, _)| *n).collect();
+        let text = std::fs::read_to_string(s).with_context(|| {
+            format!(
+                "{:?} is neither a built-in palette ({}) nor a readable file",
+                s,
+                names.join(", ")
+            )
+        })?;
+
+        Self::load(&text).with_context(|| format!("Invalid gradient file {}", s))
+    }
+}
+
+fn parse_stop(line: &str) -> Result<(f32, [f32; 3])> {
+    let mut fields = line.split_whitespace();
+    let pos = fields.next().context("Missing position")?.parse()?;
+
+    let rest: Vec<&str> = fields.collect();
+    let color = match rest.as_slice() {
+        [hex] => parse_hex(hex.trim_start_matches('#'))?,
+        [r, g, b] => [r.parse()?, g.parse()?, b.parse()?],
+        _ => bail!("Expected a #rrggbb color or three components"),
+    };
+
+    Ok((pos, color))
+}
+
+fn parse_hex(hex: &str) -> Result<[f32; 3]> {
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Expected six hex digits in color {:?}", hex);
+    }
+
+    let mut color = [0.; 3];
+    for (c, out) in color.iter_mut().enumerate() {
+        *out = u8::from_str_radix(&hex[c * 2..c * 2 + 2], 16)? as f32 / u8::MAX as f32;
+    }
+
+    Ok(color)
+}
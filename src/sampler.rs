@@ -0,0 +1,555 @@
+use crate::formula::Formula;
+use crate::histogram::Histogram;
+use crate::worker::{Orbit, Stats, Tracer};
+use crate::Opt;
+use anyhow::{bail, Result};
+use rand::distributions::Uniform;
+use rand::prelude::*;
+use rand::rngs::SmallRng;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Strategy for choosing sample points
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplerKind {
+    /// Uniformly distributed over the disc
+    Uniform,
+    /// Metropolis-Hastings, concentrating on points whose orbits reach the frame
+    Metropolis,
+    /// Proportional to a coarse map of which regions' orbits reach the frame
+    Importance,
+    /// Halton sequence in bases 2 and 3
+    Halton,
+    /// Two dimensional Sobol sequence
+    Sobol,
+    /// Roberts' R2 sequence, from the generalized golden ratio
+    R2,
+}
+
+impl FromStr for SamplerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "uniform" => Self::Uniform,
+            "metropolis" | "mh" => Self::Metropolis,
+            "importance" => Self::Importance,
+            "halton" => Self::Halton,
+            "sobol" => Self::Sobol,
+            "r2" => Self::R2,
+            _ => bail!(
+                "Unknown sampler {:?}, expected uniform, metropolis, importance, halton, sobol or r2",
+                s
+            ),
+        })
+    }
+}
+
+/// Chooses sample points and records their orbits. Every sampler converges
+/// to the same image, with the same scale per sample
+pub enum Sampler {
+    Uniform(UniformSampler),
+    Metropolis(Metropolis),
+    Importance(ImportanceSampler),
+    Quasi(QuasiSampler),
+}
+
+/// What each worker needs to set up its sampler
+pub struct SamplerInit {
+    pub rng: SmallRng,
+    /// Importance map shared between workers
+    pub map: Option<Arc<ImportanceMap>>,
+    /// Index of the worker's first point in quasi-random sequences, so that
+    /// workers draw from disjoint parts of the sequence
+    pub sequence_start: u64,
+    /// Offset added to every quasi-random point modulo 1, derived from the
+    /// seed so that differently seeded runs draw different points
+    pub sequence_offset: (f64, f64),
+}
+
+impl Sampler {
+    /// Create the sampler chosen in `args`
+    pub fn new<F: Formula>(args: &Opt, init: SamplerInit, tracer: &mut Tracer<F>) -> Self {
+        let SamplerInit {
+            rng,
+            map,
+            sequence_start,
+            sequence_offset,
+        } = init;
+
+        match (args.sampler, map) {
+            (SamplerKind::Metropolis, _) => Self::Metropolis(Metropolis::new(args, rng, tracer)),
+            (SamplerKind::Importance, Some(map)) => {
+                Self::Importance(ImportanceSampler::new(map, args.sample_unit(), rng))
+            }
+            (kind @ (SamplerKind::Halton | SamplerKind::Sobol | SamplerKind::R2), _) => {
+                Self::Quasi(QuasiSampler::new(
+                    kind,
+                    args,
+                    sequence_start,
+                    sequence_offset,
+                ))
+            }
+            _ => Self::Uniform(UniformSampler::new(args.disc, args.symmetric, rng)),
+        }
+    }
+
+    /// First quasi-random sequence index not yet drawn from, or zero for
+    /// random samplers
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Self::Quasi(s) => s.index,
+            _ => 0,
+        }
+    }
+
+    /// Take a single sample, recording it into the channel images
+    pub fn step<F: Formula>(
+        &mut self,
+        tracer: &mut Tracer<F>,
+        images: &mut [Histogram],
+        stats: &mut Stats,
+    ) {
+        match self {
+            Self::Uniform(s) => s.step(tracer, images, stats),
+            Self::Metropolis(s) => s.step(tracer, images, stats),
+            Self::Importance(s) => s.step(tracer, images, stats),
+            Self::Quasi(s) => s.step(tracer, images, stats),
+        }
+    }
+}
+
+/// Produce random points on the disc with the given radius, or only its
+/// upper half when the tracer mirrors orbits
+pub struct UniformSampler {
+    rng: SmallRng,
+    radius: f32,
+    unif: Uniform<f32>,
+    unif_y: Uniform<f32>,
+    orbit: Orbit,
+}
+
+impl UniformSampler {
+    pub fn new(radius: f32, symmetric: bool, rng: SmallRng) -> Self {
+        let bottom = if symmetric { 0. } else { -radius };
+        Self {
+            rng,
+            radius,
+            unif: Uniform::new(-radius, radius),
+            unif_y: Uniform::new(bottom, radius),
+            orbit: Orbit::default(),
+        }
+    }
+
+    /// Produce a random point on the disc
+    fn point(&mut self) -> (f32, f32) {
+        loop {
+            let (x, y) = (
+                self.unif.sample(&mut self.rng),
+                self.unif_y.sample(&mut self.rng),
+            );
+            if x * x + y * y < self.radius * self.radius {
+                return (x, y);
+            }
+        }
+    }
+
+    fn step<F: Formula>(
+        &mut self,
+        tracer: &mut Tracer<F>,
+        images: &mut [Histogram],
+        stats: &mut Stats,
+    ) {
+        let (x, y) = self.point();
+        tracer.trace(x, y, &mut self.orbit, stats);
+        self.orbit.splat(images);
+    }
+}
+
+/// Metropolis-Hastings sampling of points in proportion to how many of
+/// their orbit points land in the frame. Each step records the current
+/// point weighted by the inverse of that proportion, so the result
+/// converges to the uniformly sampled image
+pub struct Metropolis {
+    uniform: UniformSampler,
+    /// Side length of small mutations
+    mutation: f32,
+    /// Probability of proposing an independent uniform point instead
+    large: f32,
+    /// Mean contribution of uniform samples, normalizing splat weights,
+    /// in counts per orbit point
+    norm: f32,
+    /// Keep the chain in the upper half of the disc
+    symmetric: bool,
+    current: (f32, f32),
+    orbit: Orbit,
+    proposal: Orbit,
+}
+
+impl Metropolis {
+    /// Estimate the mean contribution of uniform samples and pick a
+    /// starting point among them in proportion to their contribution
+    pub fn new<F: Formula>(args: &Opt, rng: SmallRng, tracer: &mut Tracer<F>) -> Self {
+        let mut uniform = UniformSampler::new(args.disc, args.symmetric, rng);
+        let mut orbit = Orbit::default();
+        let mut candidate = Orbit::default();
+        let mut current = (0., 0.);
+        let mut stats = Stats::default();
+
+        // Keep looking a while longer if nothing reached the frame yet
+        let (mut total, mut count) = (0, 0);
+        while count < args.mh_bootstrap || (total == 0 && count < args.mh_bootstrap * 100) {
+            let (x, y) = uniform.point();
+            tracer.trace(x, y, &mut candidate, &mut stats);
+            count += 1;
+
+            let f = candidate.contribution();
+            total += f;
+            if f > 0 && uniform.rng.gen_range(0..total) < f {
+                current = (x, y);
+                std::mem::swap(&mut orbit, &mut candidate);
+            }
+        }
+
+        if total == 0 {
+            println!("No orbits reached the frame during Metropolis bootstrap");
+        }
+
+        Self {
+            uniform,
+            mutation: args.mh_mutation * args.scale,
+            large: args.mh_large,
+            norm: total as f32 / count.max(1) as f32 * args.sample_unit() as f32,
+            symmetric: args.symmetric,
+            current,
+            orbit,
+            proposal: Orbit::default(),
+        }
+    }
+
+    fn step<F: Formula>(
+        &mut self,
+        tracer: &mut Tracer<F>,
+        images: &mut [Histogram],
+        stats: &mut Stats,
+    ) {
+        let current = self.orbit.contribution();
+        if current == 0 {
+            return;
+        }
+
+        // Both kinds of proposal are symmetric, so the acceptance ratio is
+        // just the ratio of contributions
+        let rng = &mut self.uniform.rng;
+        let (x, y) = if rng.gen::<f32>() < self.large {
+            self.uniform.point()
+        } else {
+            let d = self.mutation;
+            let (x, y) = self.current;
+            let (x, y) = (x + rng.gen_range(-d..d), y + rng.gen_range(-d..d));
+
+            // Reflecting keeps mirrored chains in the upper half, and the
+            // proposal symmetric
+            (x, if self.symmetric { y.abs() } else { y })
+        };
+
+        // Points outside the disc are never sampled uniformly
+        let r = self.uniform.radius;
+        if x * x + y * y < r * r {
+            tracer.trace(x, y, &mut self.proposal, stats);
+            let ratio = self.proposal.contribution() as f32 / current as f32;
+            if self.uniform.rng.gen::<f32>() < ratio {
+                self.current = (x, y);
+                std::mem::swap(&mut self.orbit, &mut self.proposal);
+            }
+        }
+
+        let scale = self.norm / self.orbit.contribution() as f32;
+        self.orbit
+            .splat_scaled(images, scale, &mut self.uniform.rng);
+    }
+}
+
+/// Probability of sampling each cell of a grid over the disc, from how many
+/// orbit points of a few uniform samples per cell landed in the frame
+pub struct ImportanceMap {
+    grid: usize,
+    radius: f32,
+    /// Cumulative sampling probability of each cell, in row-major order
+    cdf: Vec<f64>,
+    /// Splat weight of samples from each cell: the ratio of the uniform
+    /// density over the disc to the density of sampling the cell
+    weights: Vec<f32>,
+}
+
+impl ImportanceMap {
+    pub fn build<F: Formula>(args: &Opt, tracer: &mut Tracer<F>, rng: &mut SmallRng) -> Self {
+        let grid = args.importance_grid.max(1);
+        let radius = args.disc;
+        let cell = 2. * radius / grid as f32;
+
+        // Mean contribution of samples in each cell, or None for cells which
+        // don't overlap the disc
+        let mut orbit = Orbit::default();
+        let mut stats = Stats::default();
+        let means: Vec<Option<f64>> = (0..grid * grid)
+            .map(|idx| {
+                let (i, j) = (idx % grid, idx / grid);
+                let (x0, y0) = (i as f32 * cell - radius, j as f32 * cell - radius);
+
+                let mut total = 0;
+                let mut inside = 0;
+                for _ in 0..args.importance_samples {
+                    let x = x0 + rng.gen::<f32>() * cell;
+                    let y = y0 + rng.gen::<f32>() * cell;
+                    if x * x + y * y < radius * radius {
+                        tracer.trace(x, y, &mut orbit, &mut stats);
+                        total += orbit.contribution();
+                        inside += 1;
+                    }
+                }
+
+                // Edge cells overlap the disc even if no sample landed inside
+                let nearest_x = 0f32.clamp(x0, x0 + cell);
+                let nearest_y = 0f32.clamp(y0, y0 + cell);
+                let overlaps = nearest_x * nearest_x + nearest_y * nearest_y < radius * radius;
+                overlaps.then(|| total as f64 / inside.max(1) as f64)
+            })
+            .collect();
+
+        // Give every cell in the disc some chance, so the estimate stays
+        // unbiased where the pre-pass missed
+        let (sum, count) = means
+            .iter()
+            .flatten()
+            .fold((0., 0), |(s, n), m| (s + m, n + 1));
+        let floor =
+            args.importance_floor as f64 * (sum / count.max(1) as f64).max(f64::MIN_POSITIVE);
+        let density: Vec<f64> = means.iter().map(|m| m.map_or(0., |m| m + floor)).collect();
+        let total: f64 = density.iter().sum();
+
+        let mut acc = 0.;
+        let cdf = density
+            .iter()
+            .map(|d| {
+                acc += d;
+                acc
+            })
+            .collect();
+
+        // Cell area over disc area, divided by the chance of picking the cell
+        let disc_area = std::f64::consts::PI * (radius as f64).powi(2);
+        let cell_area = (cell as f64).powi(2);
+        let weights = density
+            .iter()
+            .map(|&d| match d {
+                0. => 0.,
+                _ => (cell_area / disc_area * total / d) as f32,
+            })
+            .collect();
+
+        let reached = means.iter().flatten().filter(|&&m| m > 0.).count();
+        println!(
+            "Importance map: {} of {} cells reach the frame",
+            reached, count
+        );
+
+        Self {
+            grid,
+            radius,
+            cdf,
+            weights,
+        }
+    }
+
+    /// Pick a point in proportion to the map, along with its splat weight
+    fn pick(&self, rng: &mut SmallRng) -> (f32, f32, f32) {
+        let total = self.cdf.last().copied().unwrap_or(0.);
+        let u = rng.gen::<f64>() * total;
+        let idx = self
+            .cdf
+            .partition_point(|&c| c <= u)
+            .min(self.cdf.len() - 1);
+
+        let cell = 2. * self.radius / self.grid as f32;
+        let (i, j) = (idx % self.grid, idx / self.grid);
+        let x = (i as f32 + rng.gen::<f32>()) * cell - self.radius;
+        let y = (j as f32 + rng.gen::<f32>()) * cell - self.radius;
+
+        (x, y, self.weights[idx])
+    }
+}
+
+/// Samples cells of an importance map, reweighting splats by the inverse
+/// of their sampling probability
+pub struct ImportanceSampler {
+    map: Arc<ImportanceMap>,
+    /// Counts recorded per orbit point
+    unit: f32,
+    rng: SmallRng,
+    orbit: Orbit,
+}
+
+impl ImportanceSampler {
+    pub fn new(map: Arc<ImportanceMap>, unit: u32, rng: SmallRng) -> Self {
+        Self {
+            map,
+            unit: unit as f32,
+            rng,
+            orbit: Orbit::default(),
+        }
+    }
+
+    fn step<F: Formula>(
+        &mut self,
+        tracer: &mut Tracer<F>,
+        images: &mut [Histogram],
+        stats: &mut Stats,
+    ) {
+        let (x, y, weight) = self.map.pick(&mut self.rng);
+
+        // Corners of cells on the edge lie outside the disc
+        let r = self.map.radius;
+        if x * x + y * y >= r * r {
+            return;
+        }
+
+        tracer.trace(x, y, &mut self.orbit, stats);
+        self.orbit
+            .splat_scaled(images, weight * self.unit, &mut self.rng);
+    }
+}
+
+/// Low-discrepancy points covering the disc more evenly than random ones
+pub struct QuasiSampler {
+    kind: SamplerKind,
+    radius: f32,
+    /// Only cover the upper half of the disc
+    symmetric: bool,
+    index: u64,
+    /// Cranley-Patterson rotation of the sequence
+    offset: (f64, f64),
+    orbit: Orbit,
+}
+
+impl QuasiSampler {
+    pub fn new(kind: SamplerKind, args: &Opt, start: u64, offset: (f64, f64)) -> Self {
+        Self {
+            kind,
+            radius: args.disc,
+            symmetric: args.symmetric,
+            index: start,
+            offset,
+            orbit: Orbit::default(),
+        }
+    }
+
+    /// Point `i` of the sequence on the unit square
+    fn unit_point(&self, i: u64) -> (f64, f64) {
+        match self.kind {
+            SamplerKind::Halton => (radical_inverse(i, 2), radical_inverse(i, 3)),
+            SamplerKind::Sobol => sobol(i),
+            _ => {
+                // Inverse powers of the plastic number
+                const G: f64 = 1.324_717_957_244_746;
+                let (a1, a2) = (1. / G, 1. / (G * G));
+                ((0.5 + a1 * i as f64).fract(), (0.5 + a2 * i as f64).fract())
+            }
+        }
+    }
+
+    /// Produce the next point of the sequence on the disc
+    fn point(&mut self) -> (f32, f32) {
+        let r = self.radius;
+        loop {
+            let (u, v) = self.unit_point(self.index);
+            let (u, v) = ((u + self.offset.0).fract(), (v + self.offset.1).fract());
+            self.index += 1;
+
+            let x = (2. * u as f32 - 1.) * r;
+            let y = if self.symmetric {
+                v as f32 * r
+            } else {
+                (2. * v as f32 - 1.) * r
+            };
+            if x * x + y * y < r * r {
+                return (x, y);
+            }
+        }
+    }
+
+    fn step<F: Formula>(
+        &mut self,
+        tracer: &mut Tracer<F>,
+        images: &mut [Histogram],
+        stats: &mut Stats,
+    ) {
+        let (x, y) = self.point();
+        tracer.trace(x, y, &mut self.orbit, stats);
+        self.orbit.splat(images);
+    }
+}
+
+/// Reflect the digits of `i` in the given base about the radix point
+fn radical_inverse(mut i: u64, base: u64) -> f64 {
+    let inv = 1. / base as f64;
+    let (mut result, mut scale) = (0., inv);
+    while i > 0 {
+        result += (i % base) as f64 * scale;
+        i /= base;
+        scale *= inv;
+    }
+    result
+}
+
+/// Point `i` of the two dimensional Sobol sequence. The first dimension is
+/// the base 2 radical inverse, the second uses the direction numbers of
+/// the primitive polynomial x + 1
+fn sobol(i: u64) -> (f64, f64) {
+    let mut v = 1_u64 << 63;
+    let mut y = 0;
+    for bit in 0..64 {
+        if i & (1 << bit) != 0 {
+            y ^= v;
+        }
+        v ^= v >> 1;
+    }
+
+    let scale = 0.5f64.powi(64);
+    (i.reverse_bits() as f64 * scale, y as f64 * scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn radical_inverse_reflects_digits() {
+        let points: Vec<f64> = (0..5).map(|i| radical_inverse(i, 3)).collect();
+        assert_eq!(points, [0., 1. / 3., 2. / 3., 1. / 9., 4. / 9.]);
+    }
+
+    #[test]
+    fn sobol_starts_with_known_points() {
+        let points: Vec<(f64, f64)> = (0..8).map(sobol).collect();
+        assert_eq!(
+            points,
+            [
+                (0., 0.),
+                (0.5, 0.5),
+                (0.25, 0.75),
+                (0.75, 0.25),
+                (0.125, 0.625),
+                (0.625, 0.125),
+                (0.375, 0.375),
+                (0.875, 0.875),
+            ]
+        );
+    }
+
+    #[test]
+    fn sobol_indices_past_32_bits_do_not_wrap() {
+        assert_ne!(sobol(1 << 32 | 1), sobol(1));
+        assert_eq!(sobol(1 << 32).0, 0.5f64.powi(33));
+    }
+}
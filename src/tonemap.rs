@@ -0,0 +1,123 @@
+use crate::histogram::{Count, Histogram};
+use anyhow::{bail, Result};
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// Options controlling how counts are mapped to intensities
+#[derive(Debug, Clone, StructOpt)]
+pub struct ToneMapOpt {
+    /// Tone mapping from counts to pixels: divide (by --div), linear, log,
+    /// gamma or equalize
+    #[structopt(long, default_value = "divide")]
+    pub tonemap: ToneMap,
+
+    /// Gamma for the gamma tone map; 2 gives a square root
+    #[structopt(long, default_value = "2.0")]
+    pub gamma: f32,
+
+    /// White point for the linear, log and gamma tone maps, as a percentile
+    /// of the counts of occupied bins. For example 99.9 lets the brightest
+    /// 0.1% of bins clip
+    #[structopt(long, default_value = "100")]
+    pub white: f32,
+
+    /// Black point for the linear, log and gamma tone maps, as a percentile
+    /// of the counts of occupied bins. Defaults to a count of zero
+    #[structopt(long)]
+    pub black: Option<f32>,
+}
+
+impl ToneMapOpt {
+    /// Map each bin of the image to an intensity in `0.0..=1.0`. The divide
+    /// tone map is white at 255 times `div`, whatever the output depth
+    pub fn apply(&self, image: &Histogram, div: Count) -> Vec<f32> {
+        let bins = image.bins();
+
+        // Exposure from the distribution of occupied bins
+        let mut occupied: Vec<Count> = bins.iter().copied().filter(|&c| c > 0).collect();
+        occupied.sort_unstable();
+        let white = percentile(&occupied, self.white);
+        let black = self.black.map_or(0., |p| percentile(&occupied, p));
+
+        let range = (white - black).max(1.);
+        let level = |i: Count| ((i as f32 - black) / range).clamp(0., 1.);
+
+        match self.tonemap {
+            ToneMap::Divide => bins
+                .iter()
+                .map(|&i| (i as f32 / div as f32 / 255.).min(1.))
+                .collect(),
+            ToneMap::Linear => bins.iter().map(|&i| level(i)).collect(),
+            ToneMap::Log => {
+                let norm = range.ln_1p();
+                bins.iter()
+                    .map(|&i| (level(i) * range).ln_1p() / norm)
+                    .collect()
+            }
+            ToneMap::Gamma => bins
+                .iter()
+                .map(|&i| level(i).powf(self.gamma.recip()))
+                .collect(),
+            ToneMap::Equalize => equalize(bins),
+        }
+    }
+}
+
+/// Find the count at the given percentile of sorted counts, or zero if empty
+fn percentile(sorted: &[Count], percentile: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.;
+    }
+
+    let rank = (percentile.clamp(0., 100.) / 100. * (sorted.len() - 1) as f32).round();
+    sorted[rank as usize] as f32
+}
+
+/// Mapping from bin counts to normalized intensities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMap {
+    /// Divide counts by the channel divisor, clipping at white
+    Divide,
+    /// Scale linearly between the black and white points
+    Linear,
+    /// Logarithmic in the count between the black and white points
+    Log,
+    /// Power curve with exponent `1 / gamma` (square root for gamma 2)
+    Gamma,
+    /// Histogram equalization, spreading occupied bins evenly over the range
+    Equalize,
+}
+
+impl FromStr for ToneMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "divide" | "div" => Self::Divide,
+            "linear" => Self::Linear,
+            "log" => Self::Log,
+            "gamma" | "sqrt" => Self::Gamma,
+            "equalize" | "eq" => Self::Equalize,
+            _ => bail!(
+                "Unknown tone map {:?}, expected one of divide, linear, log, gamma, equalize",
+                s
+            ),
+        })
+    }
+}
+
+/// Map each count to the fraction of occupied bins with an equal or lower count
+fn equalize(bins: &[Count]) -> Vec<f32> {
+    let mut sorted = bins.to_vec();
+    sorted.sort_unstable();
+
+    let zeros = sorted.partition_point(|&c| c == 0);
+    let occupied = (sorted.len() - zeros).max(1) as f32;
+
+    bins.iter()
+        .map(|&i| match i {
+            0 => 0.,
+            _ => (sorted.partition_point(|&c| c <= i) - zeros) as f32 / occupied,
+        })
+        .collect()
+}
@@ -0,0 +1,290 @@
+use crate::formula::{iterate, Formula};
+use crate::histogram::{Count, Histogram};
+use crate::sampler::{Sampler, SamplerInit};
+use crate::{Channel, Opt};
+use rand::rngs::SmallRng;
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Weight of a spectral color component at full intensity
+pub const SPECTRAL_WEIGHT: u32 = 255;
+
+/// Brent-style cycle detection, comparing each point of an orbit against a
+/// reference point which is replaced at doubling intervals
+struct Periodicity {
+    tolerance: f32,
+    reference: (f32, f32),
+    power: usize,
+    lambda: usize,
+}
+
+impl Periodicity {
+    /// Start checking an orbit from its starting point `start`
+    fn new(tolerance: f32, start: (f32, f32)) -> Self {
+        Self {
+            tolerance,
+            reference: start,
+            power: 1,
+            lambda: 0,
+        }
+    }
+
+    /// Check the next point of the orbit, returning true once it has cycled
+    fn check(&mut self, (a, b): (f32, f32)) -> bool {
+        let (ra, rb) = self.reference;
+        if (a - ra).abs() + (b - rb).abs() < self.tolerance {
+            return true;
+        }
+
+        self.lambda += 1;
+        if self.lambda == self.power {
+            self.reference = (a, b);
+            self.power *= 2;
+            self.lambda = 0;
+        }
+
+        false
+    }
+}
+
+/// Counts of how samples were handled
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub samples: u64,
+    /// Samples skipped by the interior test
+    pub rejected: u64,
+    /// Samples found to be periodic before reaching the step limit
+    pub periodic: u64,
+    /// First quasi-random sequence index the worker hasn't drawn from
+    pub sequence: u64,
+}
+
+impl Stats {
+    pub fn add(&mut self, other: &Stats) {
+        self.samples += other.samples;
+        self.rejected += other.rejected;
+        self.periodic += other.periodic;
+        self.sequence = self.sequence.max(other.sequence);
+    }
+}
+
+/// Histograms accumulated by a worker since its last report
+pub struct Batch {
+    pub images: Vec<Histogram>,
+    pub stats: Stats,
+}
+
+/// The points of an orbit which land in the image, and the channels they
+/// are recorded into
+#[derive(Debug, Clone, Default)]
+pub struct Orbit {
+    /// Channels the orbit is recorded into, with what weight and for how
+    /// many of its points
+    targets: Vec<(usize, Count, usize)>,
+    /// Step and bin index of each point inside the image
+    hits: Vec<(usize, usize)>,
+}
+
+impl Orbit {
+    /// Number of points recorded in the image
+    pub fn contribution(&self) -> usize {
+        self.hits.len()
+    }
+
+    /// Record the orbit into the channel images
+    pub fn splat(&self, images: &mut [Histogram]) {
+        for &(n, idx) in &self.hits {
+            for &(c, weight, limit) in &self.targets {
+                if n < limit {
+                    images[c].add(idx, weight);
+                }
+            }
+        }
+    }
+
+    /// Record the orbit with its weights multiplied by `scale`. Fractional
+    /// weights are rounded randomly, so that counts are correct on average
+    pub fn splat_scaled(&self, images: &mut [Histogram], scale: f32, rng: &mut SmallRng) {
+        for &(n, idx) in &self.hits {
+            for &(c, weight, limit) in &self.targets {
+                if n < limit {
+                    let weight = weight as f32 * scale;
+                    let whole = weight.floor();
+                    let round_up = rng.gen::<f32>() < weight - whole;
+                    images[c].add(idx, whole as Count + round_up as Count);
+                }
+            }
+        }
+    }
+}
+
+/// Iterates orbits and finds where they land in the image
+pub struct Tracer<'a, F> {
+    args: &'a Opt,
+    channels: &'a [Channel],
+    formula: F,
+    skip_interior: bool,
+    /// Iterate up to the largest step limit of any channel
+    max_steps: usize,
+    /// Save all steps taken
+    steps: Vec<(f32, f32)>,
+}
+
+impl<'a, F: Formula> Tracer<'a, F> {
+    pub fn new(args: &'a Opt, channels: &'a [Channel], formula: F) -> Self {
+        let max_steps = channels.iter().map(|c| c.steps).max().unwrap_or(0);
+
+        Self {
+            args,
+            channels,
+            formula,
+            skip_interior: args.skip_interior(channels),
+            max_steps,
+            steps: Vec::with_capacity(max_steps),
+        }
+    }
+
+    /// Iterate the orbit of c = x + yi into `orbit`, which is left empty if
+    /// the orbit isn't recorded in any channel
+    pub fn trace(&mut self, x: f32, y: f32, orbit: &mut Orbit, stats: &mut Stats) {
+        let args = self.args;
+        orbit.targets.clear();
+        orbit.hits.clear();
+
+        // Mirrored samples count for their conjugate too
+        let count = if args.symmetric { 2 } else { 1 };
+
+        if self.skip_interior && self.formula.in_interior((x, y)) {
+            stats.rejected += count;
+            return;
+        }
+
+        let steps = &mut self.steps;
+        steps.clear();
+        let points = iterate(&self.formula, (x, y)).take(self.max_steps);
+        if let Some(tolerance) = args.periodicity {
+            let mut cycle = Periodicity::new(tolerance, self.formula.init((x, y)));
+            for point in points {
+                steps.push(point);
+
+                // Periodic orbits never escape, so aren't recorded
+                if cycle.check(point) {
+                    stats.periodic += count;
+                    return;
+                }
+            }
+        } else {
+            steps.extend(points);
+        }
+
+        // Find the channels in which the function diverged
+        let targets = &mut orbit.targets;
+        match &args.spectral {
+            Some(palette) if (args.min_steps..args.steps).contains(&steps.len()) => {
+                let t = args.spectral_index.position(steps.len(), args.steps);
+                let color = palette.sample(t);
+                targets.extend(
+                    color
+                        .iter()
+                        .map(|v| (v * SPECTRAL_WEIGHT as f32).round() as Count)
+                        .enumerate()
+                        .filter(|&(_, w)| w > 0)
+                        .map(|(c, w)| (c, w, args.steps)),
+                );
+            }
+            Some(_) => (),
+            None => targets.extend(
+                self.channels
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.records(steps.len(), args.min_steps))
+                    .map(|(i, c)| (i, 1, c.steps)),
+            ),
+        }
+
+        let Some(limit) = targets.iter().map(|&(_, _, limit)| limit).max() else {
+            return;
+        };
+
+        // Image framing
+        let scale = |x: f32| ((x / args.scale) + 1.) / 2.;
+        let aspect = args.width as f32 / args.height as f32;
+
+        // The orbit of the conjugate point is the mirror image of this one,
+        // which may land in the frame even when this one doesn't if the
+        // frame is off the real axis
+        let mirrors: &[f32] = if args.symmetric { &[1., -1.] } else { &[1.] };
+
+        for (n, &(x, y)) in steps.iter().enumerate().take(limit) {
+            // Find position in image
+            let x = scale((x - args.center_x) / aspect) * args.width as f32;
+            let bound_x = x >= 0. && x < args.width as f32;
+            if !bound_x {
+                continue;
+            }
+
+            for &mirror in mirrors {
+                let y = scale(mirror * y - args.center_y) * args.height as f32;
+                if y >= 0. && y < args.height as f32 {
+                    orbit.hits.push((n, x as usize + y as usize * args.width));
+                }
+            }
+        }
+    }
+}
+
+pub fn worker_thread<F: Formula>(
+    formula: F,
+    args: Opt,
+    channels: Vec<Channel>,
+    iters: usize,
+    init: SamplerInit,
+    batches: Sender<Batch>,
+    stop: Arc<AtomicBool>,
+) {
+    let new_images = || vec![Histogram::new(args.width, args.height); channels.len()];
+    let mut images = new_images();
+    let mut stats = Stats::default();
+
+    let mut tracer = Tracer::new(&args, &channels, formula);
+    let mut sampler = Sampler::new(&args, init, &mut tracer);
+
+    // Report partial results for checkpointing
+    let report_interval = args
+        .checkpoint
+        .as_ref()
+        .map(|_| Duration::from_secs(args.checkpoint_interval));
+    let mut last_report = Instant::now();
+
+    // Each mirrored sample stands for itself and its conjugate
+    let per_step = if args.symmetric { 2 } else { 1 };
+    let iters = iters / per_step;
+
+    for idx in 0..iters {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+
+        // Print progress
+        if idx % 100_000 == 0 {
+            println!("{}/{} ({}%)", idx, iters, idx * 100 / iters);
+
+            if report_interval.is_some_and(|i| last_report.elapsed() >= i) {
+                let images = std::mem::replace(&mut images, new_images());
+                stats.sequence = sampler.sequence();
+                let stats = std::mem::take(&mut stats);
+                let _ = batches.send(Batch { images, stats });
+                last_report = Instant::now();
+            }
+        }
+
+        stats.samples += per_step as u64;
+        sampler.step(&mut tracer, &mut images, &mut stats);
+    }
+
+    stats.sequence = sampler.sequence();
+    let _ = batches.send(Batch { images, stats });
+}